@@ -173,12 +173,19 @@ class ItemKind(str, Enum):
     food = "food"
 
 
+class ArmorSlot(str, Enum):
+    head = "head"
+    body = "body"
+    accessory = "accessory"
+
+
 class Item(pydantic.BaseModel):
     name: str
     level: int
     type: PokemonType
     description: str
     kind: ItemKind
+    slot: ArmorSlot | None = None
 
 
 def ask_mistral(prompt_parts: list[str]) -> str:
@@ -363,7 +370,7 @@ def gen_monsters(theme: str, setting_desc: str, names: list[str]):
 
 
 def gen_items(theme: str, setting_desc: str, names: list[str]):
-    instructions = "You are the game master for a difficult permadeath roguelike. Output JSON item definitions for each given item name. Valid types are pokemon types, i.e. one of: normal fire water electric grass ice fighting poison ground flying psychic bug rock ghost dragon dark steel fairy. Output fields include name, the name of the item; level, a number between 1 and 3 indicating how powerful the item is; type, the pokemon type of the equipment or weapon; kind, indicating the kind of item, one of: melee_weapon ranged_weapon armor food; and description, a two sentence description of the item. Output each item JSON on its own line. DO NOT mention abilities or gameplay mechanics in the description; instead, focus on appearance or lore."
+    instructions = "You are the game master for a difficult permadeath roguelike. Output JSON item definitions for each given item name. Valid types are pokemon types, i.e. one of: normal fire water electric grass ice fighting poison ground flying psychic bug rock ghost dragon dark steel fairy. Output fields include name, the name of the item; level, a number between 1 and 3 indicating how powerful the item is; type, the pokemon type of the equipment or weapon; kind, indicating the kind of item, one of: melee_weapon ranged_weapon armor food; slot, for armor only, where it is worn, one of: head body accessory; and description, a two sentence description of the item. Output each item JSON on its own line. DO NOT mention abilities or gameplay mechanics in the description; instead, focus on appearance or lore."
     examples = [
         (
             {
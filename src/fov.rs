@@ -2,7 +2,7 @@ use crate::{
     grid::{Offset, Pos},
     world::World,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 /// Consider 8 quadrants on a standard graph, each one an infinitely-long
 /// right triangle with one corner on the origin.
@@ -28,15 +28,54 @@ fn apply_quad_transform(quad: usize, off: Offset) -> Offset {
 }
 
 /// Uses shadowcasting to return set of positions visible from pos.
+/// Only tiles block sight; see `calculate_fov_with` for anything else.
 pub fn calculate_fov(pos: Pos, radius: i32, world: &World) -> HashSet<Pos> {
+    calculate_fov_with(pos, radius, |p| world.get_tile(p).kind.is_opaque())
+}
+
+/// Like `calculate_fov`, but `is_opaque` decides which positions block sight.
+pub fn calculate_fov_with(pos: Pos, radius: i32, is_opaque: impl Fn(Pos) -> bool) -> HashSet<Pos> {
     let mut seen = HashSet::new();
     seen.insert(pos);
     for quadrant in 0..8 {
-        cast_light(&mut seen, pos, 1, 0.0, 1.0, radius, quadrant, world);
+        cast_light(&mut seen, pos, 1, 0.0, 1.0, radius, quadrant, &is_opaque);
     }
     seen
 }
 
+/// Updates a previous FOV result after the opacity at `changed` flipped,
+/// e.g. a wall was dug out or a door opened.
+/// If `changed` wasn't visible, it can't have affected anything we could see,
+/// so the full recompute is skipped.
+pub fn update_fov_with(
+    fov: &mut HashSet<Pos>,
+    pos: Pos,
+    radius: i32,
+    changed: Pos,
+    is_opaque: impl Fn(Pos) -> bool,
+) {
+    if fov.contains(&changed) {
+        *fov = calculate_fov_with(pos, radius, is_opaque);
+    }
+}
+
+/// Accumulates the brightness contributed by each (position, radius) light source.
+/// Each source lights the tiles it can see, fading linearly with distance.
+/// Overlapping sources add up, so values may exceed 1.0.
+pub fn calculate_light(sources: &[(Pos, i32)], world: &World) -> HashMap<Pos, f32> {
+    let mut light = HashMap::new();
+    for &(source, radius) in sources {
+        for pos in calculate_fov(source, radius, world) {
+            let dist = ((pos - source).dist_squared() as f32).sqrt();
+            let brightness = 1.0 - dist / (radius + 1) as f32;
+            if brightness > 0.0 {
+                *light.entry(pos).or_insert(0.0) += brightness;
+            }
+        }
+    }
+    light
+}
+
 // Recursive function to perform the shadowcasting. See
 // http://www.roguebasin.com/index.php?title=FOV_using_recursive_shadowcasting
 // for an explanation.
@@ -49,7 +88,7 @@ fn cast_light(
     end_slope: f64,
     radius: i32,
     quad: usize,
-    world: &World,
+    is_opaque: &impl Fn(Pos) -> bool,
 ) {
     assert!(quad < 8);
     if start_slope > end_slope {
@@ -72,7 +111,7 @@ fn cast_light(
 
             seen.insert(pos);
 
-            if world.get_tile(pos).kind.is_opaque() {
+            if is_opaque(pos) {
                 if prev_blocked {
                     new_start = right_slope
                 } else {
@@ -86,7 +125,7 @@ fn cast_light(
                         left_slope,
                         radius,
                         quad,
-                        world,
+                        is_opaque,
                     );
                     new_start = right_slope;
                 }
@@ -101,3 +140,82 @@ fn cast_light(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::tests::test_world;
+    use crate::world::{TileKind, FOV_RANGE};
+
+    #[test]
+    fn overlapping_lights_add_up() {
+        let world = test_world();
+        let (a, b) = ((Pos::new(5, 5), 4), (Pos::new(9, 5), 4));
+        let mid = Pos::new(7, 5);
+        let only_a = calculate_light(&[a], &world);
+        let only_b = calculate_light(&[b], &world);
+        let both = calculate_light(&[a, b], &world);
+        assert!((both[&mid] - (only_a[&mid] + only_b[&mid])).abs() < 1e-6);
+        assert!(both[&mid] > only_a[&mid].max(only_b[&mid]));
+        // Tiles only one source reaches are unchanged.
+        let far = Pos::new(2, 5);
+        assert_eq!(both.get(&far), only_a.get(&far));
+    }
+
+    #[test]
+    fn incremental_update_matches_a_full_recompute() {
+        let mut world = test_world();
+        // A wall across the room with a closed door in it.
+        for y in 0..10 {
+            world[Pos::new(6, y)].kind = TileKind::Wall;
+        }
+        let door = Pos::new(6, 5);
+        let pos = world.player_pos;
+        let mut fov = calculate_fov(pos, FOV_RANGE, &world);
+        assert!(!fov.contains(&Pos::new(8, 5)));
+
+        world[door].kind = TileKind::Floor;
+        update_fov_with(&mut fov, pos, FOV_RANGE, door, |p| {
+            world[p].kind.is_opaque()
+        });
+        assert_eq!(fov, calculate_fov(pos, FOV_RANGE, &world));
+        assert!(fov.contains(&Pos::new(8, 5)));
+
+        // Out of sight changes leave the old result alone.
+        let hidden = Pos::new(15, 2);
+        world[hidden].kind = TileKind::Floor;
+        update_fov_with(&mut fov, pos, FOV_RANGE, hidden, |_| unreachable!());
+        assert_eq!(fov, calculate_fov(pos, FOV_RANGE, &world));
+    }
+
+    /// Guards against FOV getting accidentally slower, e.g. re-scanning tiles.
+    #[test]
+    fn fov_on_a_large_map_visits_each_tile_about_once() {
+        use crate::grid::{Rect, TileMap};
+        use rand::{Rng, SeedableRng};
+        let (center, radius) = (Pos::new(0, 0), 60);
+        let area = Rect::new_centered(center, radius * 2 + 1, radius * 2 + 1);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut walls = TileMap::new(false);
+        for pos in area {
+            walls[pos] = pos != center && rng.gen_bool(0.05);
+        }
+        let fov_counting_visits = |is_opaque: &dyn Fn(Pos) -> bool| {
+            let visits = std::cell::Cell::new(0);
+            let fov = calculate_fov_with(center, radius, |pos| {
+                visits.set(visits.get() + 1);
+                is_opaque(pos)
+            });
+            assert!(fov.iter().all(|&pos| area.contains(pos)));
+            (fov.len(), visits.get())
+        };
+
+        let (seen, visits) = fov_counting_visits(&|_| false);
+        assert_eq!(seen, area.len());
+        assert!(visits < area.len() * 11 / 10, "{visits} visits");
+
+        let (seen, visits) = fov_counting_visits(&|pos| walls[pos]);
+        assert!(seen < area.len());
+        assert!(visits < seen * 2, "{visits} visits to see {seen} tiles");
+    }
+}
@@ -31,7 +31,7 @@ macro_rules! modulo {
     };
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
 pub struct Pos {
     pub x: i32,
     pub y: i32,
@@ -45,20 +45,54 @@ impl Pos {
     pub fn adjacent_cardinal(&self) -> [Pos; 4] {
         CARDINALS.map(|c| *self + c)
     }
+
+    /// The single unit step that gets closest to `target`, or a zero offset if already there.
+    /// With diagonals, both axes move at once until one lines up. Without, the step is along
+    /// the axis with further to go, and x wins ties. `Offset::closest_dir` instead picks
+    /// the axis by quadrant, which is what gives map_gen its L-shaped corridors.
+    pub fn step_toward(self, target: Pos, allow_diagonal: bool) -> Offset {
+        let offset = target - self;
+        if allow_diagonal {
+            offset.norm()
+        } else if offset.x.abs() >= offset.y.abs() {
+            Offset {
+                x: offset.x.signum(),
+                y: 0,
+            }
+        } else {
+            Offset {
+                x: 0,
+                y: offset.y.signum(),
+            }
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub struct Offset {
     pub x: i32,
     pub y: i32,
 }
 
 impl Offset {
+    pub fn abs(self) -> Self {
+        Offset {
+            x: self.x.abs(),
+            y: self.y.abs(),
+        }
+    }
+    pub fn max_component(self) -> i32 {
+        self.x.max(self.y)
+    }
+    pub fn min_component(self) -> i32 {
+        self.x.min(self.y)
+    }
     pub fn diag_dist(self) -> i32 {
-        self.x.abs().max(self.y.abs())
+        self.abs().max_component()
     }
     pub fn mhn_dist(self) -> i32 {
-        self.x.abs() + self.y.abs()
+        let abs = self.abs();
+        abs.x + abs.y
     }
     pub fn dist_squared(self) -> i32 {
         self.x * self.x + self.y * self.y
@@ -95,6 +129,34 @@ impl Offset {
     pub fn rot_ccw(self) -> Self {
         self.flip().rot_cw()
     }
+
+    /// The compass direction this points in, like "north" or "southwest", or "here" if zero.
+    pub fn compass_name(self) -> &'static str {
+        // Count it as diagonal unless one axis clearly dominates.
+        let ns = if self.y * 2 < -self.x.abs() {
+            "north"
+        } else if self.y * 2 > self.x.abs() {
+            "south"
+        } else {
+            ""
+        };
+        let ew = if self.x * 2 < -self.y.abs() {
+            "west"
+        } else if self.x * 2 > self.y.abs() {
+            "east"
+        } else {
+            ""
+        };
+        match (ns, ew) {
+            ("north", "east") => "northeast",
+            ("north", "west") => "northwest",
+            ("south", "east") => "southeast",
+            ("south", "west") => "southwest",
+            ("", "") => "here",
+            ("", ew) => ew,
+            (ns, _) => ns,
+        }
+    }
 }
 
 impl Mul<i32> for Offset {
@@ -180,6 +242,30 @@ impl AddAssign<Offset> for Pos {
     }
 }
 
+impl From<(i32, i32)> for Pos {
+    fn from((x, y): (i32, i32)) -> Pos {
+        Pos { x, y }
+    }
+}
+
+impl From<Pos> for (i32, i32) {
+    fn from(pos: Pos) -> (i32, i32) {
+        (pos.x, pos.y)
+    }
+}
+
+impl From<(i32, i32)> for Offset {
+    fn from((x, y): (i32, i32)) -> Offset {
+        Offset { x, y }
+    }
+}
+
+impl From<Offset> for (i32, i32) {
+    fn from(offset: Offset) -> (i32, i32) {
+        (offset.x, offset.y)
+    }
+}
+
 fn get_chunk_index(pos: Pos) -> ChunkIndex {
     ChunkIndex {
         x: round_down!(pos.x, CHUNKSIZE as i32) / CHUNKSIZE as i32,
@@ -222,6 +308,26 @@ impl<Tile: Clone> TileMap<Tile> {
     }
 }
 
+impl<Tile: Clone + PartialEq> TileMap<Tile> {
+    /// Like modifying through `map[pos]`, but doesn't allocate a chunk if the
+    /// tile ends up as the default anyway.
+    pub fn modify(&mut self, pos: Pos, f: impl FnOnce(&mut Tile)) {
+        let chunk_index = get_chunk_index(pos);
+        let chunk_offset_x = modulo!(pos.x, CHUNKSIZE as i32) as usize;
+        let chunk_offset_y = modulo!(pos.y, CHUNKSIZE as i32) as usize;
+        if let Some(chunk) = self.chunks.get_mut(&chunk_index) {
+            f(&mut chunk.grid[chunk_offset_x][chunk_offset_y]);
+            return;
+        }
+        let default = &self.default_chunk.grid[chunk_offset_x][chunk_offset_y];
+        let mut tile = default.clone();
+        f(&mut tile);
+        if tile != *default {
+            self[pos] = tile;
+        }
+    }
+}
+
 impl<Tile: Clone> Index<Pos> for TileMap<Tile> {
     type Output = Tile;
 
@@ -247,7 +353,7 @@ impl<Tile: Clone> IndexMut<Pos> for TileMap<Tile> {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Rect {
     pub x1: i32,
     pub y1: i32,
@@ -355,6 +461,31 @@ impl Rect {
         }
         unreachable!()
     }
+    /// The idx-th position in iteration order (row by row), if in range.
+    pub fn nth(&self, idx: usize) -> Option<Pos> {
+        if idx >= self.len() {
+            return None;
+        }
+        let idx = idx as i32;
+        Some(Pos {
+            x: self.x1 + idx % self.width(),
+            y: self.y1 + idx / self.width(),
+        })
+    }
+    /// Every position `choose_edge` could return: the edges without corners,
+    /// or the whole rect if it's too small to have any.
+    pub fn edge_positions(&self) -> Vec<Pos> {
+        if self.width() <= 2 && self.height() <= 2 {
+            return self.into_iter().collect();
+        }
+        let corners = [
+            self.topleft(),
+            self.topright(),
+            self.bottomleft(),
+            self.bottomright(),
+        ];
+        self.border().filter(|p| !corners.contains(p)).collect()
+    }
     pub fn new(x1: i32, x2: i32, y1: i32, y2: i32) -> Self {
         assert!(x1 <= x2 && y1 <= y2);
         Rect { x1, y1, x2, y2 }
@@ -384,6 +515,17 @@ impl Rect {
         self.y2 += amt;
         self
     }
+    /// Like `shrink`, but None if there'd be nothing left instead of
+    /// collapsing to the middle.
+    pub fn try_shrink(self, amt: i32) -> Option<Self> {
+        let shrunk = Rect {
+            x1: self.x1 + amt,
+            x2: self.x2 - amt,
+            y1: self.y1 + amt,
+            y2: self.y2 - amt,
+        };
+        (shrunk.x1 <= shrunk.x2 && shrunk.y1 <= shrunk.y2).then_some(shrunk)
+    }
     pub fn shrink(mut self, amt: i32) -> Self {
         self.x1 += amt;
         self.x2 -= amt;
@@ -444,6 +586,13 @@ impl Rect {
         }
     }
 
+    /// Each position on the perimeter, corners included once.
+    pub fn border(&self) -> impl Iterator<Item = Pos> {
+        let Rect { x1, y1, x2, y2 } = *self;
+        self.into_iter()
+            .filter(move |p| p.x == x1 || p.x == x2 || p.y == y1 || p.y == y2)
+    }
+
     pub fn intersects(&self, other: &Rect) -> bool {
         self.x1 <= other.x2 && self.x2 >= other.x1 && self.y1 <= other.y2 && self.y2 >= other.y1
     }
@@ -477,3 +626,115 @@ impl IntoIterator for Rect {
         RectIter { rect: self, idx: 0 }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_components_with_negatives() {
+        let neg = Offset { x: -3, y: -7 };
+        assert_eq!(neg.abs(), Offset { x: 3, y: 7 });
+        assert_eq!(neg.max_component(), -3);
+        assert_eq!(neg.min_component(), -7);
+        let mixed = Offset { x: -5, y: 2 };
+        assert_eq!(mixed.abs(), Offset { x: 5, y: 2 });
+        assert_eq!(mixed.max_component(), 2);
+        assert_eq!(mixed.min_component(), -5);
+        assert_eq!(mixed.abs().max_component(), 5);
+        assert_eq!(mixed.diag_dist(), 5);
+        assert_eq!(mixed.mhn_dist(), 7);
+    }
+
+    #[test]
+    fn border_yields_each_perimeter_position_once() {
+        let border = Rect::new(0, 2, 0, 2).border().collect::<Vec<_>>();
+        assert_eq!(border.len(), 8);
+        assert!(!border.contains(&Pos::new(1, 1)));
+        assert_eq!(
+            border
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            8
+        );
+        let single = Rect::new(4, 4, 7, 7).border().collect::<Vec<_>>();
+        assert_eq!(single, vec![Pos::new(4, 7)]);
+    }
+
+    #[test]
+    fn modifying_to_the_default_allocates_no_chunk() {
+        let mut map = TileMap::new(0);
+        map.modify(Pos::new(5, 5), |tile| *tile = 0);
+        assert!(map.chunks.is_empty());
+        map.modify(Pos::new(5, 5), |tile| *tile = 1);
+        assert_eq!(map.chunks.len(), 1);
+        assert_eq!(map[Pos::new(5, 5)], 1);
+    }
+
+    #[test]
+    fn nth_matches_iteration_order() {
+        let rect = Rect::new(2, 4, 1, 3);
+        for (i, pos) in rect.into_iter().enumerate() {
+            assert_eq!(rect.nth(i), Some(pos));
+        }
+        assert_eq!(rect.nth(rect.len()), None);
+
+        let edges = rect.edge_positions();
+        assert_eq!(edges.len(), 4);
+        assert!(edges.iter().all(|&pos| rect.contains(pos)));
+        assert!(!edges.contains(&rect.topleft()));
+        assert!(!edges.contains(&Pos::new(3, 2)));
+    }
+
+    #[test]
+    fn try_shrink_refuses_to_invert() {
+        let rect = Rect::new(0, 4, 0, 2);
+        assert_eq!(rect.try_shrink(0), Some(rect));
+        assert_eq!(rect.try_shrink(1), Some(Rect::new(1, 3, 1, 1)));
+        assert_eq!(rect.try_shrink(2), None);
+    }
+
+    #[test]
+    fn tuple_conversions_round_trip() {
+        let pos = Pos::new(-3, 12);
+        assert_eq!(<(i32, i32)>::from(pos), (-3, 12));
+        assert_eq!(Pos::from(<(i32, i32)>::from(pos)), pos);
+        let offset = Offset { x: 7, y: -1 };
+        assert_eq!(<(i32, i32)>::from(offset), (7, -1));
+        assert_eq!(Offset::from(<(i32, i32)>::from(offset)), offset);
+    }
+
+    #[test]
+    fn step_toward_moves_one_tile_closer() {
+        let origin = Pos::new(0, 0);
+        let target = Pos::new(3, -2);
+        assert_eq!(origin.step_toward(target, true), Offset { x: 1, y: -1 });
+        assert_eq!(origin.step_toward(target, false), Offset { x: 1, y: 0 });
+        // Without diagonals, x wins ties.
+        assert_eq!(
+            origin.step_toward(Pos::new(2, 2), false),
+            Offset { x: 1, y: 0 }
+        );
+        for allow_diagonal in [false, true] {
+            assert_eq!(
+                origin.step_toward(origin, allow_diagonal),
+                Offset { x: 0, y: 0 }
+            );
+        }
+    }
+
+    #[test]
+    fn compass_names() {
+        for ((x, y), name) in [
+            ((0, 0), "here"),
+            ((0, -5), "north"),
+            ((3, 0), "east"),
+            ((4, 4), "southeast"),
+            ((-3, -2), "northwest"),
+            ((1, 5), "south"),
+        ] {
+            assert_eq!(Offset { x, y }.compass_name(), name, "({x}, {y})");
+        }
+    }
+}
@@ -7,6 +7,8 @@ use macroquad::prelude::*;
 
 pub const CHARS_PER_SECOND: f32 = 70.;
 pub const CHARS_PER_SECOND_LOADING: f32 = 80.;
+/// How many failed requests before we offer to play a pre-generated world.
+pub const OFFLINE_FALLBACK_ERRORS: usize = 3;
 
 pub const SETTINGS: [&str; 11] = [
     "Richard Adams's Watership Down",
@@ -35,7 +37,7 @@ pub const PROMPTS: [&str; 12] = [
     "As you might have guessed by this point, the game you are about to play includes AI-generated elements. Despite the implemented safety features, it is entirely possible for the underlying system to produce inaccurate or offensive content. Click \"I understand\" if you understand these risks and wish to continue, otherwise click Exit to exit the game.",
     "Very well. Please describe the setting of the game which you would like to play. It can be literally anything. For example, you could say \"{setting1}\" or \"{setting2}\" to generate fantasy/sci-fi worlds in those settings.",
     "Good. It'll take around 60 seconds to generate your prompt. In the meantime, a couple small notes.",
-    "CONTROLS\n\nPress 'q' at any time to see a summary of these controls.\nThe movement keys are hjkl/arrows.\nHold down shift and move to use your ranged weapon.\n\'i\' opens inventory\n\'.\' waits for a moment\n\',\' picks up an item\n\'0-9\' multi-selects inventory items\n\'e\' equips/eats an item.\n\'d\' drops selected items\n\'c\' combines/cooks items\n\';\' or \'/\' will inspect an item.",
+    "CONTROLS\n\nPress 'q' at any time to see a summary of these controls.\nThe movement keys are hjkl/arrows.\nHold down shift and move (or press 'f', then move) to use your ranged weapon. The modifier can be changed in Settings.\n\'i\' opens inventory\n\'.\' waits for a moment\n\',\' picks up an item\n\'0-9\' multi-selects inventory items\n\'e\' equips/eats an item.\n\'d\' drops selected items\n\'c\' combines/cooks items\n\';\' or \'/\' will inspect an item.\n\'r\' lists the recipes you've discovered.\n\'v\' describes your surroundings as text.\n\'x\' copies the explored map as text.\n\'o\' changes how the inventory is ordered.",
     "Some other notes --\n\nCrafting improves the quality of items in your inventory, and makes food more nutritious.\nMake sure you have both items selected before crafting.\nYou can craft any two items together as long as they are the same level -- even if they have different purposes.\nAll items have a type which influences how they interact with other items.\nWeapons and equipment degrade over time, you can see their current condition in the inventory.",
     "If this is a lot to remember, press \'q\' for a quick summary.",
     "If the fonts are rendering too small or large, there is a font scale slider on the bottom left.",
@@ -99,6 +101,7 @@ pub struct IntroState {
     pub exit: bool,
     pub theme: String,
     pub ready_for_generation: bool,
+    pub play_offline: bool,
     chosen_tip: String,
     chosen_settings: Vec<String>,
 }
@@ -113,6 +116,7 @@ impl IntroState {
             exit: false,
             theme: String::new(),
             ready_for_generation: false,
+            play_offline: false,
             chosen_tip: (*TIPS.choose(&mut rng).unwrap()).into(),
             chosen_settings: index::sample(&mut rng, SETTINGS.len(), 2)
                 .iter()
@@ -199,6 +203,125 @@ pub fn create_info_prompt(
     }
 }
 
+/// Joins a preview column's entries the way they're typed out, a blank line apart.
+fn join_entries(entries: &[(String, Color)]) -> String {
+    entries
+        .iter()
+        .map(|(text, _)| text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Wraps as much of the entries as the typewriter has shown so far onto the column,
+/// each entry keeping its own color.
+fn push_wrapped(
+    column: &mut Vec<(String, Color)>,
+    entries: &[(String, Color)],
+    shown: &str,
+    width: usize,
+) {
+    let mut budget = shown.chars().count();
+    for (i, (text, color)) in entries.iter().enumerate() {
+        if i > 0 {
+            if budget < 2 {
+                break;
+            }
+            budget -= 2;
+            column.push((String::new(), *color));
+        }
+        let typed = text.chars().take(budget).collect::<String>();
+        budget -= typed.chars().count();
+        for line in textwrap::wrap(&typed, width) {
+            column.push((line.into_owned(), *color));
+        }
+        if budget == 0 {
+            break;
+        }
+    }
+}
+
+/// The status line shown while content generates, naming the step that failed.
+fn gen_status(ig_state: &IgState, gave_up: bool) -> String {
+    let next_step = if gave_up { "Gave up." } else { "Retrying..." };
+    match ig_state {
+        IgState::Generating(s) => format!("Generating {s}..."),
+        IgState::Idle => "".into(),
+        IgState::TimedOut { what, count } => {
+            format!("Server timed out generating {what} (x{count}). {next_step}")
+        }
+        IgState::Error { what, msg, count } => {
+            format!("ERROR generating {what}: {msg} (x{count}). {next_step}")
+        }
+    }
+}
+
+/// Offers offline play after the server keeps failing on one step.
+fn offline_message(failing: &str) -> String {
+    format!("The server keeps failing to generate the {failing}. A simpler theme might help.")
+}
+
+/// The loading screen's two columns of typed-out content so far, as (line, color):
+/// the setting and areas on the left, the monsters and items on the right.
+fn preview_columns(
+    ig: &IdeaGuy,
+    typewriter: &mut LoadingTypewriter,
+    wrap_width: usize,
+) -> (Vec<(String, Color)>, Vec<(String, Color)>) {
+    let mut left = vec![];
+    let mut right = vec![];
+
+    match &ig.setting {
+        Some(setting) => {
+            let setting = typewriter.get_setting_text(setting);
+            push_wrapped(&mut left, &[(setting.into(), BLACK)], setting, wrap_width);
+        }
+        None => left.push(("Loading setting...".into(), BLACK)),
+    }
+    left.push((String::new(), BLACK));
+    match &ig.areas {
+        Some(areas) => {
+            let entries = areas
+                .iter()
+                .map(|a| (format!("{}: {}", a.name, a.blurb), BLACK))
+                .collect::<Vec<_>>();
+            let joined = join_entries(&entries);
+            let shown = typewriter.get_areas_text(&joined);
+            push_wrapped(&mut left, &entries, shown, wrap_width);
+        }
+        None if ig.setting.is_some() => left.push(("Loading areas...".into(), BLACK)),
+        None => {}
+    }
+
+    match &ig.monsters {
+        Some(monsters) => {
+            let entries = monsters
+                .iter()
+                .map(|m| (format!("{}: {}", m.name, m.description), BLACK))
+                .collect::<Vec<_>>();
+            let joined = join_entries(&entries);
+            let shown = typewriter.get_monsters_text(&joined);
+            push_wrapped(&mut right, &entries, shown, wrap_width);
+        }
+        None => right.push(("Loading monsters...".into(), BLACK)),
+    }
+    right.push((String::new(), BLACK));
+    match &ig.items {
+        Some(items) => {
+            let entries = items
+                .iter()
+                .map(|i| (format!("{} ({:?})", i.name, i.ty), i.ty.get_color().into()))
+                .collect::<Vec<_>>();
+            let joined = join_entries(&entries);
+            let shown = typewriter.get_items_text(&joined);
+            push_wrapped(&mut right, &entries, shown, wrap_width);
+        }
+        None if ig.monsters.is_some() => right.push(("Loading items...".into(), BLACK)),
+        None => {}
+    }
+
+    (left, right)
+}
+
 pub fn intro_loop(state: &mut IntroState, ig: &Option<IdeaGuy>) -> bool {
     let mut continuing = true;
     state.prompt_dt += get_frame_time();
@@ -206,57 +329,22 @@ pub fn intro_loop(state: &mut IntroState, ig: &Option<IdeaGuy>) -> bool {
     let font_size = screen_width() / 100.;
     let spacing = screen_width() / 90.;
     if let Some(ig) = ig {
-        if let Some(setting) = &ig.setting {
-            let setting = state.typewriter.get_setting_text(setting);
-            for (i, line) in textwrap::wrap(setting, (screen_width() / (font_size * 2.)) as usize)
-                .iter()
-                .enumerate()
-            {
-                draw_text(
-                    &line,
-                    screen_width() * 0.1,
-                    spacing * i as f32 + screen_height() * 0.1,
-                    font_size,
-                    BLACK,
-                );
-            }
-        } else {
-            draw_text(
-                "Loading setting...",
-                screen_width() * 0.1,
-                screen_height() * 0.1,
-                font_size,
-                BLACK,
-            );
-        }
+        let wrap_width = (screen_width() / (font_size * 2.)) as usize;
+        let (left, right) = preview_columns(ig, &mut state.typewriter, wrap_width);
 
-        if let Some(monsters) = &ig.monsters {
-            let monsters: Vec<_> = monsters
-                .iter()
-                .map(|m| format!("{}: {}\n", m.name, m.description))
-                .collect();
-            let monsters = monsters.join("\n");
-            let monsters = state.typewriter.get_monsters_text(&monsters);
-            for (i, line) in textwrap::wrap(monsters, (screen_width() / (font_size * 2.)) as usize)
-                .iter()
-                .enumerate()
-            {
+        let max_lines = (screen_height() * 0.8 / spacing) as usize;
+        for (lines, x) in [(left, 0.1), (right, 0.6)] {
+            // Scroll so the newest text stays on screen.
+            let skip = lines.len().saturating_sub(max_lines);
+            for (i, (line, color)) in lines.iter().skip(skip).enumerate() {
                 draw_text(
-                    &line,
-                    screen_width() * 0.6,
+                    line,
+                    screen_width() * x,
                     spacing * i as f32 + screen_height() * 0.1,
                     font_size,
-                    BLACK,
+                    *color,
                 );
             }
-        } else {
-            draw_text(
-                "Loading monsters...",
-                screen_width() * 0.6,
-                screen_height() * 0.1,
-                font_size,
-                BLACK,
-            );
         }
     }
     state.typewriter.advance();
@@ -281,12 +369,28 @@ pub fn intro_loop(state: &mut IntroState, ig: &Option<IdeaGuy>) -> bool {
             );
         } else {
             continuing = false;
-            let gen_status = match ig.as_ref().unwrap().get_state() {
-                IgState::Generating(s) => format!("Generating {s}..."),
-                IgState::Idle => "".into(),
-                IgState::Error { msg, count } => format!("ERROR: {msg} (x{count}). Retrying..."),
+            let ig_state = ig.as_ref().unwrap().get_state();
+            let (failing, error_count) = match ig_state {
+                IgState::TimedOut { what, count } | IgState::Error { what, count, .. } => {
+                    (what, count)
+                }
+                _ => ("", 0),
             };
+            let gave_up = ig.as_ref().is_some_and(|ig| ig.gave_up);
+            let gen_status = gen_status(&ig_state, gave_up);
             create_info_prompt(egui_ctx, state, &gen_status, false, false, false);
+            if error_count >= OFFLINE_FALLBACK_ERRORS || gave_up {
+                egui::Window::new("Offline")
+                    .resizable(false)
+                    .collapsible(false)
+                    .anchor(egui::Align2::CENTER_BOTTOM, egui::Vec2::new(0.0, -20.0))
+                    .show(egui_ctx, |ui| {
+                        ui.label(offline_message(failing));
+                        if ui.button("Play a pre-generated world instead").clicked() {
+                            state.play_offline = true;
+                        }
+                    });
+            }
         }
     });
     if state.step >= 5 {
@@ -296,3 +400,84 @@ pub fn intro_loop(state: &mut IntroState, ig: &Option<IdeaGuy>) -> bool {
     egui_macroquad::draw();
     continuing
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{ItemKind, PokemonType};
+
+    #[test]
+    fn failure_messages_name_the_failing_step() {
+        let timed_out = IgState::TimedOut {
+            what: "boss",
+            count: 2,
+        };
+        assert_eq!(
+            gen_status(&timed_out, false),
+            "Server timed out generating boss (x2). Retrying..."
+        );
+        let error = IgState::Error {
+            what: "monsters",
+            msg: "bad json".into(),
+            count: 3,
+        };
+        assert_eq!(
+            gen_status(&error, true),
+            "ERROR generating monsters: bad json (x3). Gave up."
+        );
+        assert!(offline_message("monsters").contains("generate the monsters"));
+    }
+
+    fn lines(column: &[(String, Color)]) -> Vec<&str> {
+        column.iter().map(|(line, _)| line.as_str()).collect()
+    }
+
+    #[test]
+    fn preview_types_out_what_has_loaded() {
+        use crate::world::tests::{area, item, monster};
+        let mut ig = crate::net::tests::idle_guy();
+        let mut typewriter = LoadingTypewriter::new();
+        let (left, right) = preview_columns(&ig, &mut typewriter, 40);
+        assert_eq!(lines(&left), ["Loading setting...", ""]);
+        assert_eq!(lines(&right), ["Loading monsters...", ""]);
+
+        ig.setting = Some("A damp cave.".into());
+        ig.areas = Some(vec![area("cellar"), area("attic")]);
+        ig.monsters = Some(vec![monster("rat", 1, false)]);
+        ig.items = Some(vec![item("sword", 1, ItemKind::MeleeWeapon)]);
+        // Nothing has been typed yet.
+        let (left, right) = preview_columns(&ig, &mut typewriter, 40);
+        assert_eq!(lines(&left), ["", "", ""]);
+        assert_eq!(lines(&right), ["", "", ""]);
+
+        // Five characters in, the first area is partly typed.
+        typewriter.areas_dt = Some(5. / CHARS_PER_SECOND_LOADING);
+        let (left, _) = preview_columns(&ig, &mut typewriter, 40);
+        assert_eq!(lines(&left), ["", "", "cella"]);
+
+        for dt in [
+            &mut typewriter.setting_dt,
+            &mut typewriter.areas_dt,
+            &mut typewriter.monsters_dt,
+            &mut typewriter.items_dt,
+        ] {
+            *dt = Some(1000.);
+        }
+        let (left, right) = preview_columns(&ig, &mut typewriter, 20);
+        assert_eq!(
+            lines(&left),
+            [
+                "A damp cave.",
+                "",
+                "cellar: You enter",
+                "the cellar.",
+                "",
+                "attic: You enter the",
+                "attic."
+            ]
+        );
+        assert_eq!(lines(&right), ["rat: A rat.", "", "sword (Normal)"]);
+        let sword_color: Color = PokemonType::Normal.get_color().into();
+        assert_eq!(right.last().unwrap().1, sword_color);
+    }
+}
@@ -9,12 +9,14 @@ mod intro;
 mod map_gen;
 mod net;
 mod render;
+mod replay;
+mod sound;
 mod util;
 #[cfg(target_family = "wasm")]
 mod wasm;
 mod world;
 
-use crate::grid::{EAST, NORTH, SOUTH, WEST};
+use crate::grid::{Offset, EAST, NORTH, SOUTH, WEST};
 
 enum GameState {
     Intro(intro::IntroState),
@@ -26,6 +28,9 @@ struct PlayState {
     memory: world::Memory,
     ui: render::Ui,
     pressed_keys: HashMap<KeyCode, f32>,
+    /// Set by the fire key: the next direction fires instead of moving.
+    fire_mode: bool,
+    recorder: Option<replay::Recorder>,
 }
 
 const KEYS_WITH_REPEAT: &[KeyCode] = &[
@@ -39,8 +44,139 @@ const KEYS_WITH_REPEAT: &[KeyCode] = &[
     KeyCode::Up,
 ];
 
-const INIT_KEY_REPEAT: f32 = 0.5;
-const KEY_REPEAT_DELAY: f32 = 1.0 / 30.0;
+/// How long a key has to be held before it repeats, and how often it repeats after that.
+#[derive(Clone, Copy)]
+pub struct KeyRepeat {
+    pub initial: f32,
+    pub delay: f32,
+}
+
+impl KeyRepeat {
+    pub const DEFAULT: KeyRepeat = KeyRepeat {
+        initial: 0.5,
+        delay: 1.0 / 30.0,
+    };
+
+    /// Whether a key held from `old` to `new` seconds should repeat this frame.
+    fn fires(self, old: f32, new: f32) -> bool {
+        if new < self.initial {
+            false
+        } else if old < self.initial {
+            true
+        } else {
+            ((old - self.initial) / self.delay).floor()
+                != ((new - self.initial) / self.delay).floor()
+        }
+    }
+}
+
+/// Default for the setting of the same name on Ui.
+pub const MOVE_KEY_REPEAT: Option<KeyRepeat> = Some(KeyRepeat::DEFAULT);
+/// Which modifier to hold while moving to fire instead.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FireModifier {
+    Shift,
+    Ctrl,
+    Alt,
+}
+
+impl FireModifier {
+    pub const ALL: [FireModifier; 3] = [FireModifier::Shift, FireModifier::Ctrl, FireModifier::Alt];
+
+    fn is_down(self) -> bool {
+        self.is_held(is_key_down)
+    }
+
+    /// Whether either side's key is down, according to `is_key_down`.
+    fn is_held(self, is_key_down: impl Fn(KeyCode) -> bool) -> bool {
+        let (left, right) = match self {
+            FireModifier::Shift => (KeyCode::LeftShift, KeyCode::RightShift),
+            FireModifier::Ctrl => (KeyCode::LeftControl, KeyCode::RightControl),
+            FireModifier::Alt => (KeyCode::LeftAlt, KeyCode::RightAlt),
+        };
+        is_key_down(left) || is_key_down(right)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FireModifier::Shift => "SHIFT",
+            FireModifier::Ctrl => "CTRL",
+            FireModifier::Alt => "ALT",
+        }
+    }
+}
+
+fn direction_action(dir: Offset, firing: bool) -> PlayerAction {
+    if firing {
+        PlayerAction::Fire(dir)
+    } else {
+        PlayerAction::Move(dir)
+    }
+}
+
+/// Press this, then a direction, to fire without holding the modifier.
+const FIRE_MODE_KEY: KeyCode = KeyCode::F;
+
+/// Escape or a fresh direction cancels any repeats still pending, so held
+/// keys can't queue up extra moves or shots.
+fn cancel_pending_repeats(
+    pressed_keys: &mut HashMap<KeyCode, f32>,
+    is_pressed: impl Fn(KeyCode) -> bool,
+) {
+    if is_pressed(KeyCode::Escape) || KEYS_WITH_REPEAT.iter().any(|k| is_pressed(*k)) {
+        pressed_keys.clear();
+    }
+}
+
+/// macroquad doesn't report focus changes, and key releases made while
+/// unfocused are never seen. Hidden windows stop drawing, so forget held keys
+/// after a long stall.
+fn forget_keys_after_stall(pressed_keys: &mut HashMap<KeyCode, f32>, frame_time: f32) {
+    if frame_time > FOCUS_LOSS_FRAME_TIME {
+        pressed_keys.clear();
+    }
+}
+
+/// Gameplay pauses while the level transition plays: held keys are dropped
+/// and this frame's key press is ignored.
+fn pause_input(
+    paused: bool,
+    pressed_keys: &mut HashMap<KeyCode, f32>,
+    key: Option<KeyCode>,
+) -> Option<KeyCode> {
+    if paused {
+        pressed_keys.clear();
+        return None;
+    }
+    key
+}
+
+/// Adds a frame to each held key's timer and returns the keys due to repeat.
+fn advance_key_repeats(
+    pressed_keys: &mut HashMap<KeyCode, f32>,
+    repeat: Option<KeyRepeat>,
+    frame_time: f32,
+) -> Vec<KeyCode> {
+    let mut keys_to_repeat = vec![];
+    for (k, v) in pressed_keys.iter_mut() {
+        let Some(repeat) = repeat else {
+            // Wait the full initial delay again once the modifier is released.
+            *v = 0.0;
+            continue;
+        };
+        let old_v = *v;
+        *v += frame_time;
+        if repeat.fires(old_v, *v) {
+            keys_to_repeat.push(*k);
+        }
+    }
+    keys_to_repeat
+}
+
+/// Modifier+direction fires, and holding it shouldn't empty a ranged weapon.
+pub const FIRE_KEY_REPEAT: Option<KeyRepeat> = None;
+/// Frames longer than this mean the window was probably hidden or unfocused.
+const FOCUS_LOSS_FRAME_TIME: f32 = 0.25;
 
 #[cfg(target_family = "wasm")]
 pub fn random() -> u64 {
@@ -61,16 +197,27 @@ impl PlayState {
         assert!(ig.items.is_some());
         let mut sim = world::World::new();
         sim.update_defs(ig);
-        map_gen::generate_world(&mut sim, random());
-        let memory = world::Memory::new();
-        let ui = render::Ui::new(None, font);
+        let replay = replay::InputLog::from_env();
+        let seed = replay.as_ref().map(|log| log.seed).unwrap_or_else(random);
+        map_gen::generate_world(&mut sim, seed);
+        let mut memory = world::Memory::new();
+        let mut ui = render::Ui::new(None, font);
         sim.post_init();
+        memory.update(&sim);
+        if let Some(log) = replay {
+            log.replay(&mut sim, |sim| memory.update(sim));
+            // Keep the replay's rules rather than resetting them on the first frame.
+            ui.rules = sim.rules;
+        }
         let pressed_keys = HashMap::new();
+        let recorder = replay::Recorder::from_env(seed);
         let mut slf = Self {
             sim,
             ui,
             memory,
             pressed_keys,
+            fire_mode: false,
+            recorder,
         };
         slf.update_memory();
 
@@ -80,7 +227,7 @@ impl PlayState {
     pub fn equip(&mut self) -> bool {
         let mut tick = false;
         if let Some(&min) = self.ui.inventory_selected.iter().min() {
-            tick |= self.sim.do_player_action(PlayerAction::Use(min));
+            tick |= self.do_player_action(PlayerAction::Use(min));
             self.ui.inventory_selected.remove(&min);
         }
         tick
@@ -96,11 +243,10 @@ impl PlayState {
             .collect::<Vec<_>>();
         selected.sort();
         if selected.len() >= 2 {
-            tick |= self
-                .sim
-                .do_player_action(PlayerAction::Craft(selected[0], selected[1]));
-            self.ui.inventory_selected.remove(&selected[0]);
-            self.ui.inventory_selected.remove(&selected[1]);
+            for i in &selected {
+                self.ui.inventory_selected.remove(i);
+            }
+            tick |= self.do_player_action(PlayerAction::Craft(selected));
         }
         tick
     }
@@ -108,13 +254,16 @@ impl PlayState {
     pub fn drop(&mut self) -> bool {
         let mut tick = false;
         if let Some(&min) = self.ui.inventory_selected.iter().min() {
-            tick |= self.sim.do_player_action(PlayerAction::Drop(min));
+            tick |= self.do_player_action(PlayerAction::Drop(min));
             self.ui.inventory_selected.remove(&min);
         }
         tick
     }
 
     pub fn inspect(&mut self) {
+        if self.ui.inventory_selected.is_empty() {
+            self.sim.inspect_surroundings();
+        }
         for item in self.ui.inventory_selected.iter() {
             if let Some(item) = self.sim.inventory.items.get(*item).map(|x| &x.item) {
                 self.sim.log_message(vec![match item {
@@ -122,14 +271,29 @@ impl PlayState {
                         format!("{}: {}", ii.info.name, ii.info.description.clone()),
                         ii.info.ty.get_color(),
                     ),
-                    world::Item::PendingCraft(_, _) => {
-                        ("Crafting in progress...".into(), net::Color::Pink)
+                    world::Item::PendingCraft(a, b) => {
+                        (self.sim.get_craft_status(a, b).describe(), net::Color::Pink)
                     }
                 }]);
             }
         }
     }
 
+    fn regenerate_floor(&mut self) {
+        let level = self.sim.level_id();
+        match map_gen::regenerate_level(&mut self.sim, level) {
+            Ok(_) => {
+                // What the player remembers of the old floor no longer applies.
+                self.memory = world::Memory::new();
+                self.sim.log_message(vec![(
+                    format!("Regenerated floor {level}."),
+                    net::Color::White,
+                )]);
+            }
+            Err(e) => macroquad::miniquad::error!("{}", e),
+        }
+    }
+
     pub fn handle_buttons(&mut self) {
         // Handle in-game UI button presses.
         let tick = if let Some(ui_button) = self.ui.ui_button {
@@ -150,6 +314,15 @@ impl PlayState {
                     self.inspect();
                     false
                 }
+                render::UiButton::RegenerateFloor => {
+                    self.regenerate_floor();
+                    false
+                }
+                render::UiButton::Move(_) | render::UiButton::Wait | render::UiButton::PickUp => {
+                    ui_button
+                        .player_action(self.ui.touch_fire)
+                        .is_some_and(|action| self.do_player_action(action))
+                }
             }
         } else {
             false
@@ -160,51 +333,61 @@ impl PlayState {
         }
     }
 
+    /// Fires if the fire modifier is held or fire mode is on, otherwise moves.
+    fn move_or_fire(&mut self, dir: Offset) -> bool {
+        // Always clear fire mode, even if the modifier made it moot.
+        let fire_mode = std::mem::take(&mut self.fire_mode);
+        let firing = self.ui.fire_modifier.is_down() || fire_mode;
+        self.do_player_action(direction_action(dir, firing))
+    }
+
     pub fn handle_key(&mut self, key: KeyCode) {
         let mut tick = false;
         match key {
             KeyCode::L | KeyCode::Right => {
-                if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                    tick |= self.sim.do_player_action(PlayerAction::Fire(EAST));
-                } else {
-                    tick |= self.sim.do_player_action(PlayerAction::Move(EAST));
-                }
+                tick |= self.move_or_fire(EAST);
             }
             KeyCode::H | KeyCode::Left => {
-                if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                    tick |= self.sim.do_player_action(PlayerAction::Fire(WEST));
-                } else {
-                    tick |= self.sim.do_player_action(PlayerAction::Move(WEST));
-                }
+                tick |= self.move_or_fire(WEST);
             }
             KeyCode::J | KeyCode::Down => {
-                if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                    tick |= self.sim.do_player_action(PlayerAction::Fire(SOUTH));
-                } else {
-                    tick |= self.sim.do_player_action(PlayerAction::Move(SOUTH));
-                }
+                tick |= self.move_or_fire(SOUTH);
             }
             KeyCode::K | KeyCode::Up => {
-                if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                    tick |= self.sim.do_player_action(PlayerAction::Fire(NORTH));
-                } else {
-                    tick |= self.sim.do_player_action(PlayerAction::Move(NORTH));
-                }
+                tick |= self.move_or_fire(NORTH);
             }
             KeyCode::I => {
                 self.ui.toggle_ui();
                 tick = false
             }
             KeyCode::Comma | KeyCode::G => {
-                tick |= self.sim.do_player_action(PlayerAction::PickUp);
+                tick |= self.do_player_action(PlayerAction::PickUp);
             }
             KeyCode::Period | KeyCode::Space => {
-                tick |= self.sim.do_player_action(PlayerAction::Wait);
+                tick |= self.do_player_action(PlayerAction::Wait);
             }
             KeyCode::E | KeyCode::A => tick |= self.equip(),
             KeyCode::C => tick |= self.craft(),
             KeyCode::D => tick |= self.drop(),
             KeyCode::Q => self.ui.toggle_help(),
+            KeyCode::R => self.ui.toggle_recipes(),
+            KeyCode::V => self.ui.toggle_description(),
+            KeyCode::O => {
+                let order = self.sim.inventory.order.next();
+                self.sim.inventory.order = order;
+                self.sim.log_message(vec![(
+                    format!("Inventory is now kept {}.", order.describe()),
+                    net::Color::White,
+                )]);
+            }
+            KeyCode::X => {
+                let text = render::export_map_text(&self.sim, &self.memory);
+                miniquad::window::clipboard_set(&text);
+                self.sim.log_message(vec![(
+                    "Copied the map to the clipboard.".into(),
+                    net::Color::White,
+                )]);
+            }
             KeyCode::Slash | KeyCode::Semicolon => {
                 if matches!(key, KeyCode::Slash)
                     && (is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift))
@@ -215,8 +398,16 @@ impl PlayState {
                     self.inspect();
                 }
             }
+            FIRE_MODE_KEY => {
+                self.fire_mode = !self.fire_mode;
+                if self.fire_mode {
+                    self.sim
+                        .log_message(vec![("Fire in which direction?".into(), net::Color::White)]);
+                }
+            }
             KeyCode::Escape => {
                 self.ui.ui_selected = false;
+                self.fire_mode = false;
             }
             _ => {
                 let key = key as usize;
@@ -240,15 +431,26 @@ impl PlayState {
         }
     }
 
-    fn update_memory(&mut self) {
-        let seen = fov::calculate_fov(self.sim.get_player_pos(), world::FOV_RANGE, &self.sim);
-        self.memory.mobs.clear();
-        for pos in seen {
-            self.memory.tile_map[pos] = Some(self.sim.get_tile(pos));
-            if let Some(mob) = self.sim.get_mob(pos) {
-                self.memory.mobs.insert(pos, mob.clone());
-            }
+    fn do_player_action(&mut self, action: PlayerAction) -> bool {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&replay::InputEvent::Action(action.clone()));
+        }
+        self.sim.do_player_action(action)
+    }
+
+    /// Takes the rules from the settings window, recording any change.
+    fn set_rules(&mut self, rules: world::Rules) {
+        if rules == self.sim.rules {
+            return;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&replay::InputEvent::Rules(rules));
         }
+        self.sim.rules = rules;
+    }
+
+    fn update_memory(&mut self) {
+        self.memory.update(&self.sim);
     }
 
     fn tick(&mut self) {
@@ -337,6 +539,7 @@ fn window_conf() -> Conf {
 #[macroquad::main(window_conf)]
 async fn main() {
     let font = load_ttf_font("assets/DejaVuSansMono.ttf").await.unwrap();
+    let sounds = sound::Sounds::load().await;
     egui_startup();
     egui_update_scaling(1.0);
 
@@ -367,6 +570,12 @@ async fn main() {
                 if intro.ready_for_generation && ig.is_none() {
                     ig = Some(IdeaGuy::new(&intro.theme));
                 }
+                if intro.play_offline {
+                    intro.play_offline = false;
+                    if let Some(ref mut ig) = ig {
+                        ig.go_offline();
+                    }
+                }
                 let intro_waiting = intro::intro_loop(intro, &ig);
                 if !intro_waiting && ig.as_ref().filter(|ig| ig.boss.is_some()).is_some() {
                     GameState::Play(PlayState::new(font.clone(), ig.as_mut().unwrap()))
@@ -379,34 +588,41 @@ async fn main() {
             }
             GameState::Play(ref mut ps) => {
                 let ig = ig.as_mut().unwrap();
+                ps.set_rules(ps.ui.rules);
                 ps.sim.update_defs(ig);
-                if let Some(key) = get_last_key_pressed() {
+                // Key repeat, once per second
+                forget_keys_after_stall(&mut ps.pressed_keys, get_frame_time());
+                let paused = ps.ui.in_level_transition();
+                let last_key = pause_input(paused, &mut ps.pressed_keys, get_last_key_pressed());
+                // Keys pressed again this frame get a fresh timer below.
+                ps.pressed_keys
+                    .retain(|k, _v| is_key_down(*k) && !is_key_pressed(*k));
+                cancel_pending_repeats(&mut ps.pressed_keys, is_key_pressed);
+                let firing = ps.ui.fire_modifier.is_down();
+                let repeat = if firing {
+                    ps.ui.fire_key_repeat
+                } else {
+                    ps.ui.move_key_repeat
+                };
+                let keys_to_repeat =
+                    advance_key_repeats(&mut ps.pressed_keys, repeat, get_frame_time());
+                for k in keys_to_repeat {
+                    ps.handle_key(k);
+                }
+                // Only start timing new presses after repeats are handled, so a
+                // long frame can't turn a fresh press into two actions.
+                if let Some(key) = last_key {
                     ps.handle_key(key);
                     if KEYS_WITH_REPEAT.contains(&key) {
                         ps.pressed_keys.insert(key, 0.0);
                     }
                 }
-                // Key repeat, once per second
-                ps.pressed_keys.retain(|k, _v| is_key_down(*k));
-                let mut keys_to_repeat = vec![];
-                for (k, v) in ps.pressed_keys.iter_mut() {
-                    let old_v = *v;
-                    *v += get_frame_time();
-                    if *v >= INIT_KEY_REPEAT {
-                        if old_v < INIT_KEY_REPEAT {
-                            keys_to_repeat.push(*k);
-                        } else if ((old_v - INIT_KEY_REPEAT) / KEY_REPEAT_DELAY).floor()
-                            != ((*v - INIT_KEY_REPEAT) / KEY_REPEAT_DELAY).floor()
-                        {
-                            keys_to_repeat.push(*k);
-                        }
-                    }
-                }
-                for k in keys_to_repeat {
-                    ps.handle_key(k);
-                }
 
-                ps.handle_buttons();
+                if paused {
+                    ps.ui.ui_button = None;
+                } else {
+                    ps.handle_buttons();
+                }
 
                 // Handle animations
                 for untriggered_animation in ps.sim.untriggered_animations.iter() {
@@ -414,6 +630,12 @@ async fn main() {
                 }
                 ps.sim.untriggered_animations.clear();
 
+                for effect in ps.sim.untriggered_sounds.drain(..) {
+                    if ps.ui.sound_enabled {
+                        sounds.play(effect);
+                    }
+                }
+
                 ps.ui.render(&ps.sim, &ps.memory);
                 gs
             }
@@ -422,3 +644,88 @@ async fn main() {
         next_frame().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_keys_wait_then_repeat_once_per_delay() {
+        let repeat = KeyRepeat {
+            initial: 0.5,
+            delay: 0.1,
+        };
+        assert!(!repeat.fires(0.0, 0.4));
+        assert!(repeat.fires(0.4, 0.5));
+        assert!(!repeat.fires(0.5, 0.55));
+        assert!(repeat.fires(0.55, 0.65));
+        // One repeat per frame, even if a long frame spans several delays.
+        assert!(repeat.fires(0.65, 1.0));
+    }
+
+    #[test]
+    fn escape_or_a_new_direction_cancels_repeats() {
+        let held = || HashMap::from([(KeyCode::Left, 0.7)]);
+        let mut pressed_keys = held();
+        cancel_pending_repeats(&mut pressed_keys, |_| false);
+        assert_eq!(pressed_keys.len(), 1);
+        cancel_pending_repeats(&mut pressed_keys, |k| k == KeyCode::I);
+        assert_eq!(pressed_keys.len(), 1);
+        cancel_pending_repeats(&mut pressed_keys, |k| k == KeyCode::Escape);
+        assert!(pressed_keys.is_empty());
+        let mut pressed_keys = held();
+        cancel_pending_repeats(&mut pressed_keys, |k| k == KeyCode::Up);
+        assert!(pressed_keys.is_empty());
+    }
+
+    #[test]
+    fn held_keys_are_dropped_after_losing_focus() {
+        let mut pressed_keys = HashMap::from([(KeyCode::Left, 0.2)]);
+        forget_keys_after_stall(&mut pressed_keys, 1.0 / 60.0);
+        assert_eq!(pressed_keys.len(), 1);
+        // An unfocused window stops drawing, so the next frame is a long one.
+        forget_keys_after_stall(&mut pressed_keys, 3.0);
+        assert!(pressed_keys.is_empty());
+    }
+
+    #[test]
+    fn a_fresh_press_is_one_action_on_its_frame() {
+        let mut pressed_keys = HashMap::new();
+        // Repeats run before the press starts its timer, so even a long
+        // press frame leaves the press itself as the only action.
+        assert!(advance_key_repeats(&mut pressed_keys, MOVE_KEY_REPEAT, 2.0).is_empty());
+        pressed_keys.insert(KeyCode::Left, 0.0);
+        // The next short frame doesn't repeat yet.
+        assert!(advance_key_repeats(&mut pressed_keys, MOVE_KEY_REPEAT, 0.01).is_empty());
+    }
+
+    #[test]
+    fn input_is_ignored_while_paused() {
+        let mut pressed_keys = HashMap::from([(KeyCode::L, 0.3)]);
+        assert_eq!(pause_input(true, &mut pressed_keys, Some(KeyCode::L)), None);
+        assert!(pressed_keys.is_empty());
+        assert_eq!(
+            pause_input(false, &mut pressed_keys, Some(KeyCode::L)),
+            Some(KeyCode::L)
+        );
+    }
+
+    #[test]
+    fn only_the_configured_modifier_fires() {
+        let ctrl_held = |key| key == KeyCode::RightControl;
+        let action = |modifier: FireModifier| direction_action(EAST, modifier.is_held(ctrl_held));
+        assert!(matches!(
+            action(FireModifier::Ctrl),
+            PlayerAction::Fire(EAST)
+        ));
+        assert!(matches!(
+            action(FireModifier::Shift),
+            PlayerAction::Move(EAST)
+        ));
+        assert!(matches!(
+            action(FireModifier::Alt),
+            PlayerAction::Move(EAST)
+        ));
+        assert!(!FireModifier::Ctrl.is_held(|_| false));
+    }
+}
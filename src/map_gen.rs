@@ -1,10 +1,11 @@
 #![allow(dead_code)]
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashSet};
 use std::rc::Rc;
 
 use rand::rngs::StdRng;
 use rand::Rng;
 use rand::{seq::SliceRandom, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
 use crate::grid::{Offset, Pos, Rect, TileMap, CARDINALS};
 use crate::net::{ItemKind, MapGen};
@@ -45,7 +46,7 @@ pub fn carve_rooms_bsp(
             let wall = get_connecting_wall(room, *adj).unwrap();
             let has_door = wall.into_iter().any(|pos| world[pos].kind.is_walkable());
             if !has_door {
-                carve_floor(world, wall.choose(rng), 0, opts.floor);
+                carve_floor(world, wall.choose(rng), Brush::POINT, opts.floor);
             }
         }
     }
@@ -66,7 +67,7 @@ pub fn carve_rooms_bsp_extra_loops(
             let room2 = rooms.choose(rng).unwrap();
             if let Some(wall) = get_connecting_wall(*room1, *room2) {
                 let pos = wall.choose(rng);
-                carve_floor(world, pos, 0, opts.floor);
+                carve_floor(world, pos, Brush::POINT, opts.floor);
                 break;
             }
         }
@@ -141,7 +142,8 @@ impl BspTree {
 }
 
 struct RoomGraph {
-    pub room_adj: HashMap<Rect, Vec<Rect>>,
+    /// Ordered so that a seed picks the same rooms on every run.
+    pub room_adj: BTreeMap<Rect, Vec<Rect>>,
 }
 
 impl RoomGraph {
@@ -186,7 +188,7 @@ impl RoomGraph {
     }
     fn new() -> Self {
         Self {
-            room_adj: HashMap::new(),
+            room_adj: BTreeMap::new(),
         }
     }
     fn add_room(&mut self, room: Rect) {
@@ -256,7 +258,7 @@ pub fn carve_line_drunk(
     world: &mut World,
     start: Pos,
     end: Pos,
-    brush_size: u8,
+    brush: Brush,
     rng: &mut impl Rng,
     waviness: f64,
     tile: TileKind,
@@ -273,16 +275,16 @@ pub fn carve_line_drunk(
             continue;
         }
         pos += dir;
-        carve_floor(world, pos, brush_size, tile);
+        carve_floor(world, pos, brush, tile);
     }
 }
 
-pub fn carve_line(world: &mut World, start: Pos, end: Pos, brush_size: u8, tile: TileKind) {
+pub fn carve_line(world: &mut World, start: Pos, end: Pos, brush: Brush, tile: TileKind) {
     // based on https://www.redblobgames.com/grids/line-drawing.html (2.1)
-    carve_floor(world, start, brush_size, tile);
+    carve_floor(world, start, brush, tile);
     let mut pos = start;
     let offset = end - start;
-    let (nx, ny) = (offset.x.abs(), offset.y.abs());
+    let Offset { x: nx, y: ny } = offset.abs();
     let (mut ix, mut iy) = (0, 0);
     while (ix, iy) != (nx, ny) {
         if (1 + 2 * ix) * ny < (1 + 2 * iy) * nx {
@@ -292,14 +294,14 @@ pub fn carve_line(world: &mut World, start: Pos, end: Pos, brush_size: u8, tile:
             pos.y += offset.y.signum();
             iy += 1;
         }
-        carve_floor(world, pos, brush_size, tile);
+        carve_floor(world, pos, brush, tile);
     }
 }
 
 pub fn carve_corridor(world: &mut World, start: Pos, end: Pos, tile: TileKind) {
     let mut pos = start;
     while pos != end {
-        carve_floor(world, pos, 0, tile);
+        carve_floor(world, pos, Brush::POINT, tile);
         pos += (end - pos).closest_dir();
     }
 }
@@ -308,7 +310,7 @@ pub fn fill_rect(world: &mut World, rect: Rect, kind: TileKind) {
     for x in rect.x1..=rect.x2 {
         for y in rect.y1..=rect.y2 {
             let pos = Pos { x, y };
-            world[pos].kind = kind;
+            world.modify_tile(pos, |t| t.kind = kind);
         }
     }
 }
@@ -327,7 +329,16 @@ fn gen_alien_nest(world: &mut World, rng: &mut impl Rng, entrances: &[Pos], rect
     // draw lines between interior entrances
     for &e1 in &interior_entrances {
         for &e2 in interior_entrances.iter().chain(&[rect.center()]) {
-            carve_line_drunk(world, e1, e2, 0, rng, 0.5, TileKind::YellowFloor, rect);
+            carve_line_drunk(
+                world,
+                e1,
+                e2,
+                Brush::POINT,
+                rng,
+                0.5,
+                TileKind::YellowFloor,
+                rect,
+            );
         }
     }
     // spawn some enemies
@@ -344,7 +355,11 @@ fn gen_alien_nest(world: &mut World, rng: &mut impl Rng, entrances: &[Pos], rect
     }
 }
 
-fn gen_offices(world: &mut World, rng: &mut impl Rng, rect: Rect) -> LevelgenResult {
+fn gen_offices(
+    world: &mut World,
+    rng: &mut impl Rng,
+    rect: Rect,
+) -> Result<LevelgenResult, String> {
     let max_width = rng.gen_range(4..=rect.width().min(8));
     let min_width = max_width / 2 - 1;
     let max_height = rng.gen_range(4..=rect.width().min(8));
@@ -374,10 +389,13 @@ fn gen_offices(world: &mut World, rng: &mut impl Rng, rect: Rect) -> LevelgenRes
             }
         }
     }
-    LevelgenResult {
+    if rooms.len() < 2 {
+        return Err(format!("Only placed {} rooms", rooms.len()));
+    }
+    Ok(LevelgenResult {
         start: rooms[0].center(),
         end: rooms.iter().last().unwrap().center(),
-    }
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -388,26 +406,46 @@ pub struct SimpleRoomOpts {
     pub max_room_size: i32,
 }
 
+/// Enemies per walkable tile. A typical level has around 1000 walkable tiles.
+pub const ENEMY_DENSITY: f32 = 30. / 1000.;
+/// Items of each kind per walkable tile.
+pub const ITEM_DENSITY: f32 = 12. / 1000.;
+
 pub struct SprinkleOpts {
-    pub num_enemies: usize,
-    pub num_armor: usize,
-    pub num_weapons: usize,
-    pub num_food: usize,
+    pub enemy_density: f32,
+    pub armor_density: f32,
+    pub weapon_density: f32,
+    pub food_density: f32,
     pub enemies: Vec<MobKind>,
     pub items: Vec<Rc<ItemInfo>>,
-    pub difficulty: usize,
+    /// (weight, monster level) pairs. Levels with no matching monsters,
+    /// including anything outside 1..=3, fall back to any enemy.
+    pub enemy_level_weights: Vec<(u32, usize)>,
+}
+
+/// How likely each monster level is to spawn, by how deep the level is.
+pub fn default_enemy_level_weights(difficulty: usize) -> Vec<(u32, usize)> {
+    match difficulty {
+        0 => vec![(7, 1), (2, 2), (1, 3)],
+        1 => vec![(3, 1), (5, 2), (1, 3)],
+        _ => vec![(3, 1), (3, 2), (4, 3)],
+    }
 }
 
 pub fn gen_simple_rooms(
     world: &mut World,
     opts: &SimpleRoomOpts,
     rng: &mut impl Rng,
-) -> LevelgenResult {
+) -> Result<LevelgenResult, String> {
     // Create rooms
     let mut rooms = vec![];
     for _ in 0..opts.max_rooms {
         let w = rng.gen_range(opts.min_room_size..=opts.max_room_size);
         let h = rng.gen_range(opts.min_room_size..=opts.max_room_size);
+        if opts.rect.x2 - w < opts.rect.x1 || opts.rect.y2 - h < opts.rect.y1 {
+            // Room doesn't fit in the rect at all.
+            continue;
+        }
         let x = rng.gen_range(opts.rect.x1..=opts.rect.x2 - w);
         let y = rng.gen_range(opts.rect.y1..=opts.rect.y2 - h);
         let new_room = Rect::new(x, x + w, y, y + h);
@@ -416,6 +454,9 @@ pub fn gen_simple_rooms(
             rooms.push(new_room);
         }
     }
+    if rooms.len() < 2 {
+        return Err(format!("Only placed {} rooms", rooms.len()));
+    }
     // Draw corridors
     let mut connected: HashSet<usize> = HashSet::new();
     for (i, room) in rooms.iter().enumerate() {
@@ -440,7 +481,7 @@ pub fn gen_simple_rooms(
     // Write rooms on top of corridors
     for room in rooms.iter().copied() {
         for pos in room {
-            carve_floor(world, pos, 0, TileKind::Floor)
+            carve_floor(world, pos, Brush::POINT, TileKind::Floor)
         }
     }
     let topleft_room = rooms
@@ -452,10 +493,10 @@ pub fn gen_simple_rooms(
         .max_by_key(|r| (r.center() - topleft_room.center()).mhn_dist())
         .unwrap();
 
-    LevelgenResult {
+    Ok(LevelgenResult {
         start: topleft_room.center(),
         end: bottomright_room.center(),
-    }
+    })
 }
 
 pub struct LevelgenResult {
@@ -548,9 +589,13 @@ fn sprinkle_items(
     rng: &mut impl Rng,
 ) -> usize {
     for i in 0..num {
-        let pos = match poses.pop() {
-            Some(pos) => pos,
-            None => return i,
+        // Skip spots another pass already put an item on.
+        let pos = loop {
+            match poses.pop() {
+                Some(pos) if world[pos].item.is_some() => continue,
+                Some(pos) => break pos,
+                None => return i,
+            }
         };
         if let Some(ii) = items.choose(rng).cloned() {
             world[pos].item = Some(Item::Instance(ItemInstance::new(
@@ -585,12 +630,10 @@ fn sprinkle_enemies_and_items(
         .filter(|pos| !fov.contains(pos))
         .collect::<Vec<_>>();
 
+    let scaled = |density: f32| (density * walkable_poses.len() as f32).round() as usize;
+    let num_enemies = scaled(sprinkle.enemy_density).min(walkable_poses_out_of_fov.len());
+
     // Sprinkle enemies/items
-    let enemy_level_weight = match sprinkle.difficulty {
-        0 => &[(7, 1), (2, 2), (1, 3)],
-        1 => &[(3, 1), (5, 2), (1, 3)],
-        _ => &[(3, 1), (3, 2), (4, 3)],
-    };
     let enemies_per_level = (1..=3)
         .map(|i| {
             sprinkle
@@ -602,21 +645,24 @@ fn sprinkle_enemies_and_items(
         })
         .collect::<Vec<_>>();
 
-    for _ in 0..sprinkle.num_enemies {
+    for _ in 0..num_enemies {
         let pos = match walkable_poses_out_of_fov.choose(rng) {
             Some(pos) => *pos,
             None => return Err("Failed to find pos out of fov".into()),
         };
-        let desired_level = enemy_level_weight
+        let desired_level = sprinkle
+            .enemy_level_weights
             .choose_weighted(rng, |wl| wl.0)
-            .unwrap()
-            .1;
-        if let Some(mob_info) = enemies_per_level[desired_level - 1].choose(rng) {
+            .map_or(0, |wl| wl.1);
+        let leveled_enemies = desired_level
+            .checked_sub(1)
+            .and_then(|i| enemies_per_level.get(i));
+        if let Some(mob_info) = leveled_enemies.and_then(|e| e.choose(rng)) {
             // Try to pick enemies with a good level distribution.
-            world.add_mob(pos, Mob::new(*mob_info));
+            world.add_mob(pos, Mob::new(*mob_info, world.get_mobkind_info(*mob_info)));
         } else if let Some(mob_info) = sprinkle.enemies.choose(rng) {
             // Fall back to any enemy.
-            world.add_mob(pos, Mob::new(*mob_info));
+            world.add_mob(pos, Mob::new(*mob_info, world.get_mobkind_info(*mob_info)));
         } else {
             macroquad::miniquad::error!("No mobs available in level");
         }
@@ -637,9 +683,9 @@ fn sprinkle_enemies_and_items(
     let mut item_poses = walkable_poses.clone();
     item_poses.shuffle(rng);
     for (num, items, name) in &[
-        (sprinkle.num_armor, &armor, "armor"),
-        (sprinkle.num_weapons, &weapons, "weapons"),
-        (sprinkle.num_food, &food, "food"),
+        (scaled(sprinkle.armor_density), &armor, "armor"),
+        (scaled(sprinkle.weapon_density), &weapons, "weapons"),
+        (scaled(sprinkle.food_density), &food, "food"),
     ] {
         let placed = sprinkle_items(world, &mut item_poses, *num, items, rng);
         macroquad::miniquad::info!("{}", format!("Placed {placed}/{num} {name}"));
@@ -652,7 +698,9 @@ fn sprinkle_enemies_and_items(
             .cloned()
             .filter(|p| world[*p].kind.is_walkable())
             .collect();
-        free_poses_near_player.sort_by_key(|p| (*p - lgr.start).mhn_dist());
+        // The fov is a HashSet; break distance ties by position so a seed
+        // places the same starting items every run.
+        free_poses_near_player.sort_by_key(|p| ((*p - lgr.start).mhn_dist(), *p));
         free_poses_near_player.reverse();
         for (num, items, name) in &[
             (2, &armor, "starting armor"),
@@ -664,7 +712,11 @@ fn sprinkle_enemies_and_items(
         }
     }
     // make some tiles bloody just for fun
-    for p in walkable_poses {
+    for &p in &walkable_poses {
+        // Leave stairs and anything with an item on it alone.
+        if world[p].kind != TileKind::Floor || world[p].item.is_some() {
+            continue;
+        }
         let gen = rng.gen::<f32>();
         if gen < 0.1 {
             world[p].kind = TileKind::BloodyFloor;
@@ -672,6 +724,20 @@ fn sprinkle_enemies_and_items(
             world[p].kind = TileKind::YellowFloor;
         }
     }
+    // mount some torches on walls next to the floor
+    let mut torch_poses = rect
+        .into_iter()
+        .filter(|pos| world[*pos].kind == TileKind::Wall)
+        .filter(|pos| {
+            pos.adjacent_cardinal()
+                .iter()
+                .any(|adj| world[*adj].kind.is_walkable())
+        })
+        .collect::<Vec<_>>();
+    torch_poses.shuffle(rng);
+    for pos in torch_poses.into_iter().take(walkable_poses.len() / 150) {
+        world[pos].kind = TileKind::Torch;
+    }
     Ok(())
 }
 
@@ -682,18 +748,30 @@ enum LevelGenType {
     DenseRooms,
 }
 
-fn generate_level(world: &mut World, i: usize, rng: &mut StdRng) -> Result<LevelgenResult, String> {
+/// The mapgen crate only takes a StdRng, so derive one from our seeded rng.
+fn mapgen_rng(rng: &mut impl Rng) -> StdRng {
+    StdRng::seed_from_u64(rng.gen())
+}
+
+fn generate_level(
+    world: &mut World,
+    i: usize,
+    rng: &mut ChaCha8Rng,
+) -> Result<LevelgenResult, String> {
     let algo = world.world_info.areas[i].mapgen;
     let sprinkle = SprinkleOpts {
-        num_enemies: 30,
-        num_armor: 12,
-        num_weapons: 12,
-        num_food: 12,
+        enemy_density: ENEMY_DENSITY,
+        armor_density: ITEM_DENSITY,
+        weapon_density: ITEM_DENSITY,
+        food_density: ITEM_DENSITY,
         enemies: world.world_info.monsters_per_level[i].clone(),
-        items: world.world_info.equipment_per_level[i].clone(),
-        difficulty: i,
+        items: level_item_pool(world, i),
+        enemy_level_weights: world.world_info.areas[i]
+            .enemy_level_weights
+            .clone()
+            .unwrap_or_else(|| default_enemy_level_weights(i)),
     };
-    let rect = Rect::new_centered(Pos::new(i as i32 * 100, 0), 80, 50);
+    let rect = level_rect(i);
     let lgr = match algo {
         MapGen::SimpleRoomsAndCorridors => {
             let opts = SimpleRoomOpts {
@@ -714,8 +792,8 @@ fn generate_level(world: &mut World, i: usize, rng: &mut StdRng) -> Result<Level
                 ))
                 .with(mapgen::CullUnreachable::new())
                 .with(mapgen::DistantExit::new())
-                .build_with_rng(rng);
-            gen_level_mapgen(world, buf, rect, rng)
+                .build_with_rng(&mut mapgen_rng(rng));
+            Ok(gen_level_mapgen(world, buf, rect, rng))
         }
         MapGen::Hive => {
             let buf = mapgen::MapBuilder::new(80, 50)
@@ -725,8 +803,8 @@ fn generate_level(world: &mut World, i: usize, rng: &mut StdRng) -> Result<Level
                     mapgen::YStart::TOP,
                 ))
                 .with(mapgen::DistantExit::new())
-                .build_with_rng(rng);
-            gen_level_mapgen(world, buf, rect, rng)
+                .build_with_rng(&mut mapgen_rng(rng));
+            Ok(gen_level_mapgen(world, buf, rect, rng))
         }
         MapGen::DenseRooms => {
             // too dense for big rect
@@ -734,6 +812,13 @@ fn generate_level(world: &mut World, i: usize, rng: &mut StdRng) -> Result<Level
             gen_offices(world, rng, rect)
         }
     };
+    let lgr = match lgr {
+        Ok(lgr) => lgr,
+        Err(e) => {
+            fill_rect(world, rect, TileKind::Wall);
+            return Err(e);
+        }
+    };
     let total_reachable = rect
         .into_iter()
         .filter(|p| world[*p].kind.is_walkable())
@@ -746,46 +831,743 @@ fn generate_level(world: &mut World, i: usize, rng: &mut StdRng) -> Result<Level
     sprinkle_enemies_and_items(world, rect, i, &lgr, &sprinkle, rng).map(|_| lgr)
 }
 
+/// Items that can spawn on level i. If none of the level's items got
+/// defined, borrows the pool of the nearest level that has some.
+fn level_item_pool(world: &World, i: usize) -> Vec<Rc<ItemInfo>> {
+    let pools = &world.world_info.equipment_per_level;
+    if !pools[i].is_empty() {
+        return pools[i].clone();
+    }
+    for dist in 1..pools.len() {
+        for j in [i.checked_sub(dist), Some(i + dist)].into_iter().flatten() {
+            if let Some(pool) = pools.get(j).filter(|pool| !pool.is_empty()) {
+                macroquad::miniquad::warn!(
+                    "{}",
+                    format!("No items for level {i}, using level {j}'s")
+                );
+                return pool.clone();
+            }
+        }
+    }
+    macroquad::miniquad::error!("{}", format!("No items for level {i} or any other"));
+    vec![]
+}
+
+/// Debug builds only: setting START_LEVEL=n starts the run on level n (0-based).
+#[cfg(debug_assertions)]
+fn debug_start_level() -> usize {
+    std::env::var("START_LEVEL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+#[cfg(not(debug_assertions))]
+fn debug_start_level() -> usize {
+    0
+}
+
+/// A weapon of each kind and a piece of armor from the level before, as if the
+/// player had fought their way down.
+fn debug_starting_gear(world: &World, level: usize) -> Vec<Rc<ItemInfo>> {
+    let pool = level_item_pool(world, level - 1);
+    [
+        ItemKind::MeleeWeapon,
+        ItemKind::RangedWeapon,
+        ItemKind::Armor,
+    ]
+    .into_iter()
+    .filter_map(|kind| pool.iter().find(|ii| ii.kind == kind).cloned())
+    .collect()
+}
+
+fn level_rect(i: usize) -> Rect {
+    Rect::new_centered(Pos::new(i as i32 * 100, 0), 80, 50)
+}
+
+/// Puts each area's mini-boss, if any, as far from the start of its level as possible.
+fn place_mini_bosses(world: &mut World, results: &[LevelgenResult]) {
+    for (level, lgr) in results.iter().enumerate() {
+        let Some(kind) = world
+            .world_info
+            .mini_bosses
+            .get(level)
+            .and_then(|b| b.as_ref())
+            .map(|b| b.mob_kind)
+        else {
+            continue;
+        };
+        let pos = level_rect(level)
+            .into_iter()
+            .filter(|p| world[*p].kind.is_walkable() && world[*p].kind != TileKind::Stairs)
+            .filter(|p| world.get_mob(*p).is_none())
+            .max_by_key(|p| (*p - lgr.start).mhn_dist());
+        match pos {
+            Some(pos) => world.add_mob(pos, Mob::new(kind, world.get_mobkind_info(kind))),
+            None => macroquad::miniquad::error!(
+                "{}",
+                format!("No room for the mini-boss on level {level}")
+            ),
+        }
+    }
+}
+
+/// Puts each unique item once, as far from the start as possible on the
+/// level that lists it, or on the last level if none do.
+fn place_unique_items(world: &mut World, results: &[LevelgenResult]) {
+    if results.is_empty() {
+        return;
+    }
+    let mut placed = HashSet::new();
+    let uniques = world
+        .world_info
+        .item_kinds
+        .iter()
+        .filter(|ii| ii.unique)
+        .cloned()
+        .collect::<Vec<_>>();
+    for ii in uniques {
+        if !placed.insert(ii.name.clone()) {
+            continue;
+        }
+        let level = world
+            .world_info
+            .areas
+            .iter()
+            .position(|area| {
+                area.equipment
+                    .iter()
+                    .chain(&area.melee_weapons)
+                    .chain(&area.ranged_weapons)
+                    .chain(&area.food)
+                    .any(|name| name == &ii.name)
+            })
+            .unwrap_or(results.len() - 1)
+            .min(results.len() - 1);
+        let start = results[level].start;
+        let pos = level_rect(level)
+            .into_iter()
+            .filter(|p| world[*p].kind.is_walkable() && world[*p].kind != TileKind::Stairs)
+            .filter(|p| world[*p].item.is_none())
+            .max_by_key(|p| (*p - start).mhn_dist());
+        match pos {
+            Some(pos) => {
+                world[pos].item = Some(Item::Instance(ItemInstance::new(
+                    ii.clone(),
+                    world::STARTING_DURABILITY,
+                )));
+                macroquad::miniquad::info!(
+                    "{}",
+                    format!("Placed unique {} on level {level}", ii.name)
+                );
+            }
+            None => macroquad::miniquad::error!("{}", format!("No room for unique {}", ii.name)),
+        }
+    }
+}
+
+/// Small levels can end where they start. Move the start so the player
+/// doesn't spawn on the stairs and descend on their first step.
+fn keep_start_off_stairs(world: &World, level: usize, lgr: &mut LevelgenResult) {
+    if lgr.start != lgr.end {
+        return;
+    }
+    let start = lgr.start;
+    let pos = level_rect(level)
+        .into_iter()
+        .filter(|&p| p != lgr.end && world[p].kind.is_walkable())
+        .min_by_key(|&p| (p - start).dist_squared());
+    match pos {
+        Some(pos) => {
+            macroquad::miniquad::warn!(
+                "{}",
+                format!("Level {level} starts on its stairs, moving start to {pos:?}")
+            );
+            lgr.start = pos;
+        }
+        None => macroquad::miniquad::error!(
+            "{}",
+            format!("Level {level} has nowhere to start but its stairs")
+        ),
+    }
+}
+
+/// Each level gets its own seed so one floor can be regenerated on its own.
+pub fn level_seed(run_seed: u64, level: usize) -> u64 {
+    // splitmix64, so nearby levels get unrelated seeds.
+    let mut z = run_seed.wrapping_add((level as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Generates level `i` from its own seed, retrying until it works.
+fn generate_seeded_level(world: &mut World, i: usize, level_seed: u64) -> LevelgenResult {
+    let mut level_rng = ChaCha8Rng::seed_from_u64(level_seed);
+    loop {
+        let algo = world.world_info.areas[i].mapgen;
+        match generate_level(world, i, &mut level_rng) {
+            Ok(lgr) => return lgr,
+            Err(e) => {
+                macroquad::miniquad::error!("{}", format!("{algo:?} levelgen failed: {e}"));
+            }
+        }
+    }
+}
+
+/// Generates level `i` again from its stored seed, in place, so one floor can
+/// be reproduced without replaying the ones before it. Its stairs are relinked
+/// and the player moved to its start if they were on it. Unique items and
+/// mini-bosses aren't placed again.
+pub fn regenerate_level(world: &mut World, i: usize) -> Result<LevelgenResult, String> {
+    let level_seed = *world
+        .level_seeds
+        .get(i)
+        .ok_or_else(|| format!("Level {i} has no seed"))?;
+    let rect = level_rect(i);
+    let exits = world.clear_area(rect);
+    let mut lgr = generate_seeded_level(world, i, level_seed);
+    keep_start_off_stairs(world, i, &mut lgr);
+    world.retarget_stairs(rect, lgr.start);
+    for dest in exits {
+        world.add_stairs(lgr.end, dest);
+    }
+    if rect.contains(world.player_pos) {
+        world.player_pos = lgr.start;
+    }
+    Ok(lgr)
+}
+
 pub fn generate_world(world: &mut World, seed: u64) {
     macroquad::miniquad::info!("seed: {}", seed);
-    let mut rng = StdRng::seed_from_u64(seed);
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    world.seed_rng(seed);
     let mut results = vec![];
+    world.level_seeds.clear();
     for i in 0..world.world_info.areas.len() {
-        let lgr = loop {
-            let algo = world.world_info.areas[i].mapgen;
-            match generate_level(world, i, &mut rng) {
-                Ok(lgr) => break lgr,
-                Err(e) => {
-                    macroquad::miniquad::error!("{}", format!("{algo:?} levelgen failed: {e}"));
-                }
-            }
-        };
-        results.push(lgr);
+        let level_seed = level_seed(seed, i);
+        macroquad::miniquad::info!("{}", format!("level {i} seed: {level_seed}"));
+        world.level_seeds.push(level_seed);
+        results.push(generate_seeded_level(world, i, level_seed));
+    }
+    for (i, lgr) in results.iter_mut().enumerate() {
+        keep_start_off_stairs(world, i, lgr);
     }
-    world.player_pos = results[0].start;
     for i in 1..results.len() {
         world.add_stairs(results[i - 1].end, results[i].start)
     }
+    place_unique_items(world, &results);
+    place_mini_bosses(world, &results);
     // final boss room
-    let fb_rect = Rect::new_centered(Pos::new(80 * 4, 0), 12, 12);
-    for pos in fb_rect {
-        carve_floor(world, pos, 0, TileKind::YellowFloor);
-    }
-    world.add_stairs(
-        results.iter().last().unwrap().end,
-        fb_rect.bottom_edge().choose(&mut rng),
-    );
-    let boss_kind = world.world_info.boss_info.as_ref().unwrap().mob_kind;
-    world.add_mob(fb_rect.top_edge().center(), Mob::new(boss_kind));
-}
-
-pub fn carve_floor(world: &mut World, pos: Pos, brush_size: u8, tile: TileKind) {
-    let brush_size = brush_size as i32;
-    let brush_floor = -brush_size / 2;
-    let brush_ceil = brush_floor + brush_size;
-    for dx in brush_floor..=brush_ceil {
-        for dy in brush_floor..=brush_ceil {
-            world[pos + Offset { x: dx, y: dy }].kind = tile;
+    let (entrance, boss_pos) = gen_boss_arena(world, Pos::new(80 * 4, 0), &mut rng);
+    match (results.first(), results.last()) {
+        (Some(first), Some(last)) => {
+            world.player_pos = first.start;
+            world.add_stairs(last.end, entrance);
+            let start_level = debug_start_level().min(results.len() - 1);
+            if start_level > 0 {
+                macroquad::miniquad::info!("{}", format!("Starting on level {start_level}"));
+                let gear = debug_starting_gear(world, start_level);
+                world.start_at_level(start_level, results[start_level].start, gear);
+            }
+        }
+        _ => {
+            // A partial generation can get here with no areas. Better to
+            // drop the player straight into the boss room than crash.
+            macroquad::miniquad::error!("No areas to generate, starting at the boss");
+            world.player_pos = entrance;
         }
     }
+    world.world_info.ensure_boss();
+    if let Some(boss_kind) = world.world_info.boss_info.as_ref().map(|b| b.mob_kind) {
+        world.add_mob(
+            boss_pos,
+            Mob::new(boss_kind, world.get_mobkind_info(boss_kind)),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BossArena {
+    Square,
+    Pillared,
+    Circular,
+    /// A square with hazard tiles scattered around a safe middle column.
+    Hazardous,
+}
+
+impl BossArena {
+    const ALL: [BossArena; 4] = [
+        BossArena::Square,
+        BossArena::Pillared,
+        BossArena::Circular,
+        BossArena::Hazardous,
+    ];
+}
+
+/// Carves out the final boss room. Returns where the player enters and where
+/// the boss stands, which are always connected.
+fn gen_boss_arena(world: &mut World, center: Pos, rng: &mut impl Rng) -> (Pos, Pos) {
+    let arena = *BossArena::ALL.choose(rng).unwrap();
+    macroquad::miniquad::info!("{}", format!("Boss arena: {arena:?}"));
+    carve_boss_arena(world, center, arena, rng)
+}
+
+fn carve_boss_arena(
+    world: &mut World,
+    center: Pos,
+    arena: BossArena,
+    rng: &mut impl Rng,
+) -> (Pos, Pos) {
+    let rect = Rect::new_centered(center, 12, 12);
+    match arena {
+        BossArena::Square => {
+            for pos in rect {
+                carve_floor(world, pos, Brush::POINT, TileKind::YellowFloor);
+            }
+            (rect.bottom_edge().choose(rng), rect.top_edge().center())
+        }
+        BossArena::Pillared => {
+            for pos in rect {
+                carve_floor(world, pos, Brush::POINT, TileKind::YellowFloor);
+            }
+            // Pillars on a 3-tile grid, kept off the middle column so the
+            // boss always has a straight line to the entrance.
+            for pos in rect.try_shrink(1).into_iter().flatten() {
+                let off = pos - rect.topleft();
+                if off.x % 3 == 1 && off.y % 3 == 1 && pos.x != rect.center().x {
+                    world[pos].kind = TileKind::YellowWall;
+                }
+            }
+            (rect.bottom_edge().center(), rect.top_edge().center())
+        }
+        BossArena::Circular => {
+            let radius = (rect.width() - 1) / 2;
+            carve_floor(
+                world,
+                rect.center(),
+                Brush::Circle(radius as u8),
+                TileKind::YellowFloor,
+            );
+            (
+                rect.center() + Offset { x: 0, y: radius },
+                rect.center() + Offset { x: 0, y: -radius },
+            )
+        }
+        BossArena::Hazardous => {
+            for pos in rect {
+                carve_floor(world, pos, Brush::POINT, TileKind::YellowFloor);
+            }
+            // Small patches of hazards. Like the pillars, they stay off the
+            // middle column, so there's always a safe way to the boss.
+            for pos in rect.try_shrink(2).into_iter().flatten() {
+                if (pos.x - rect.center().x).abs() >= 2 && rng.gen::<f32>() < 0.1 {
+                    carve_floor(world, pos, Brush::Diamond(1), TileKind::Hazard);
+                }
+            }
+            (rect.bottom_edge().center(), rect.top_edge().center())
+        }
+    }
+}
+
+/// Shape stamped at each point by the carving functions.
+#[derive(Debug, Clone, Copy)]
+pub enum Brush {
+    /// A square (size + 1) tiles wide. Size 0 is a single tile.
+    Square(u8),
+    /// Tiles within this many cardinal steps.
+    Diamond(u8),
+    /// Tiles within this (euclidean) radius.
+    Circle(u8),
+}
+
+impl Brush {
+    pub const POINT: Brush = Brush::Square(0);
+
+    fn offsets(self) -> Vec<Offset> {
+        let (lo, hi) = match self {
+            Brush::Square(size) => {
+                let lo = -(size as i32) / 2;
+                (lo, lo + size as i32)
+            }
+            Brush::Diamond(r) | Brush::Circle(r) => (-(r as i32), r as i32),
+        };
+        let mut offsets = vec![];
+        for dx in lo..=hi {
+            for dy in lo..=hi {
+                let off = Offset { x: dx, y: dy };
+                let inside = match self {
+                    Brush::Square(_) => true,
+                    Brush::Diamond(r) => off.mhn_dist() <= r as i32,
+                    Brush::Circle(r) => off.dist_squared() <= (r as i32) * (r as i32),
+                };
+                if inside {
+                    offsets.push(off);
+                }
+            }
+        }
+        offsets
+    }
+}
+
+pub fn carve_floor(world: &mut World, pos: Pos, brush: Brush, tile: TileKind) {
+    for off in brush.offsets() {
+        world[pos + off].kind = tile;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::tests::test_world;
+
+    /// Floors `rect` in the test world, walled off from the player's room,
+    /// and sprinkles it like a level past the first.
+    fn sprinkled(rect: Rect) -> World {
+        sprinkled_with(rect, |_, _| {})
+    }
+
+    /// Like `sprinkled`, with a chance to change the floor or options first.
+    fn sprinkled_with(rect: Rect, setup: impl FnOnce(&mut World, &mut SprinkleOpts)) -> World {
+        let mut world = test_world();
+        for pos in rect {
+            world[pos].kind = TileKind::Floor;
+        }
+        let mut sprinkle = SprinkleOpts {
+            enemy_density: ENEMY_DENSITY,
+            armor_density: ITEM_DENSITY,
+            weapon_density: ITEM_DENSITY,
+            food_density: ITEM_DENSITY,
+            enemies: world.world_info.monsters_per_level[0].clone(),
+            items: world.world_info.equipment_per_level[0].clone(),
+            enemy_level_weights: vec![(1, 1)],
+        };
+        setup(&mut world, &mut sprinkle);
+        let lgr = LevelgenResult {
+            start: world.player_pos,
+            end: world.player_pos,
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        sprinkle_enemies_and_items(&mut world, rect, 1, &lgr, &sprinkle, &mut rng).unwrap();
+        world
+    }
+
+    #[test]
+    fn smaller_areas_get_fewer_enemies() {
+        let small = sprinkled(Rect::new(30, 39, 0, 9));
+        let large = sprinkled(Rect::new(30, 69, 0, 39));
+        // Enemies can land on the same tile, so counts may come up a little short.
+        let expected = |tiles: f32| (ENEMY_DENSITY * tiles).round() as usize;
+        assert!((1..=expected(100.)).contains(&small.mobs.len()));
+        assert!(large.mobs.len() > expected(1600.) * 9 / 10);
+    }
+
+    #[test]
+    fn items_never_share_a_tile() {
+        let rect = Rect::new(30, 69, 0, 39);
+        let world = sprinkled(rect);
+        let placed = rect
+            .into_iter()
+            .filter(|p| world[*p].item.is_some())
+            .count();
+        // Armor, weapons and food each get their own share, none overwritten.
+        assert_eq!(placed, 3 * (ITEM_DENSITY * 1600.).round() as usize);
+    }
+
+    #[test]
+    fn cosmetic_floor_skips_stairs_and_items() {
+        let rect = Rect::new(30, 69, 0, 39);
+        let stairs = Pos::new(40, 10);
+        let world = sprinkled_with(rect, |world, _| world[stairs].kind = TileKind::Stairs);
+        assert_eq!(world[stairs].kind, TileKind::Stairs);
+        for pos in rect {
+            if world[pos].item.is_some() {
+                assert_eq!(world[pos].kind, TileKind::Floor);
+            }
+        }
+        assert!(rect
+            .into_iter()
+            .any(|p| world[p].kind == TileKind::BloodyFloor));
+    }
+
+    #[test]
+    fn every_boss_arena_connects_the_entrance_to_the_boss() {
+        for arena in BossArena::ALL {
+            let mut world = World::new();
+            let mut rng = ChaCha8Rng::seed_from_u64(0);
+            let (entrance, boss) = carve_boss_arena(&mut world, Pos::new(40, 40), arena, &mut rng);
+            // Reachable without stepping on a hazard, too.
+            for pos in Rect::new_centered(Pos::new(40, 40), 12, 12) {
+                if world[pos].kind == TileKind::Hazard {
+                    world[pos].kind = TileKind::Wall;
+                }
+            }
+            let path = world.path_full(entrance, boss, 200, false, false).unwrap();
+            assert_eq!(path.last(), Some(&boss), "{arena:?}");
+        }
+    }
+
+    /// Generates a whole world from these areas, the test items and `extra_items`.
+    fn generated(areas: &[crate::net::Area], extra_items: &[crate::net::ItemDefinition]) -> World {
+        generated_with_seed(areas, extra_items, 0)
+    }
+
+    fn generated_with_seed(
+        areas: &[crate::net::Area],
+        extra_items: &[crate::net::ItemDefinition],
+        seed: u64,
+    ) -> World {
+        use crate::world::tests::{item, monster};
+        let mut items = vec![
+            item("sword", 1, ItemKind::MeleeWeapon),
+            item("bow", 1, ItemKind::RangedWeapon),
+            item("mail", 1, ItemKind::Armor),
+            item("bread", 1, ItemKind::Food),
+        ];
+        items.extend_from_slice(extra_items);
+        let mut world = World::new();
+        world.world_info.add_definitions(
+            areas,
+            &items,
+            &[monster("rat", 1, false), monster("archer", 1, true)],
+            &crate::net::fallback_boss(),
+        );
+        generate_world(&mut world, seed);
+        world
+    }
+
+    #[test]
+    fn unique_items_are_placed_once_per_run() {
+        use crate::world::tests::{area, item};
+        let mut crown = item("crown", 1, ItemKind::Armor);
+        crown.unique = true;
+        let mut areas = vec![area("cellar"), area("attic"), area("roof")];
+        for area in &mut areas {
+            area.equipment.push("crown".into());
+        }
+        let world = generated(&areas, &[crown]);
+        let crowns = (0..areas.len())
+            .flat_map(level_rect)
+            .filter(
+                |p| matches!(&world[*p].item, Some(Item::Instance(i)) if i.info.name == "crown"),
+            )
+            .count();
+        assert_eq!(crowns, 1);
+    }
+
+    #[test]
+    fn enemy_level_weights_pick_the_spawned_levels() {
+        let rect = Rect::new(30, 69, 0, 39);
+        let archers = |weights: Vec<(u32, usize)>| {
+            let world = sprinkled_with(rect, |world, sprinkle| {
+                let archer = crate::world::tests::kind_named(world, "archer");
+                world.world_info.monster_kinds[archer.0].level = 2;
+                sprinkle.enemy_level_weights = weights;
+            });
+            let archer = crate::world::tests::kind_named(&world, "archer");
+            let count = world.mobs.values().filter(|m| m.kind == archer).count();
+            (count, world.mobs.len())
+        };
+        let (none, _) = archers(vec![(1, 1)]);
+        let (all, total) = archers(vec![(1, 2)]);
+        let (some, _) = archers(vec![(1, 1), (1, 2)]);
+        assert_eq!(none, 0);
+        assert_eq!(all, total);
+        assert!(0 < some && some < total);
+    }
+
+    #[test]
+    fn a_level_without_defined_items_borrows_a_neighbors() {
+        use crate::world::tests::area;
+        let mut bare = area("cellar");
+        bare.equipment = vec!["ghost armor".into()];
+        bare.melee_weapons = vec!["ghost sword".into()];
+        bare.ranged_weapons = vec![];
+        bare.food = vec![];
+        let world = generated(&[bare, area("attic")], &[]);
+        assert!(world.world_info.equipment_per_level[0].is_empty());
+        let start = world.player_pos;
+        let starting_items = crate::fov::calculate_fov(start, FOV_RANGE, &world)
+            .into_iter()
+            .filter(|p| world[*p].item.is_some())
+            .count();
+        assert!(starting_items > 0);
+    }
+
+    #[test]
+    fn brush_shapes() {
+        let carved = |brush| {
+            let mut world = World::new();
+            carve_floor(&mut world, Pos::new(0, 0), brush, TileKind::Floor);
+            Rect::new_centered(Pos::new(0, 0), 9, 9)
+                .into_iter()
+                .filter(|p| world[*p].kind == TileKind::Floor)
+                .count()
+        };
+        assert_eq!(carved(Brush::POINT), 1);
+        assert_eq!(carved(Brush::Square(2)), 9);
+        assert_eq!(carved(Brush::Diamond(1)), 5);
+        assert_eq!(carved(Brush::Diamond(2)), 13);
+        assert_eq!(carved(Brush::Circle(1)), 5);
+        assert_eq!(carved(Brush::Circle(3)), 29);
+    }
+
+    #[test]
+    fn simple_rooms_in_a_tiny_rect_is_an_error() {
+        let mut world = World::new();
+        let opts = SimpleRoomOpts {
+            rect: Rect::new(0, 3, 0, 3),
+            max_rooms: 10,
+            min_room_size: 5,
+            max_room_size: 8,
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        assert!(gen_simple_rooms(&mut world, &opts, &mut rng).is_err());
+    }
+
+    #[test]
+    fn empty_world_info_generates_a_winnable_world() {
+        let mut world = World::new();
+        generate_world(&mut world, 0);
+        world.post_init();
+        let boss = world.world_info.boss_info.as_ref().unwrap().mob_kind;
+        assert!(world.mobs.values().any(|mob| mob.kind == boss));
+        assert!(world[world.player_pos].kind.is_walkable());
+    }
+
+    #[test]
+    fn level_seeds_are_stable_and_distinct() {
+        let seeds = (0..10)
+            .map(|level| level_seed(7, level))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            seeds,
+            (0..10)
+                .map(|level| level_seed(7, level))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(seeds.iter().collect::<HashSet<_>>().len(), 10);
+        assert_ne!(level_seed(8, 3), seeds[3]);
+    }
+
+    /// Everything generated on level `i`: tiles, item names and monster kinds.
+    fn floor_snapshot(
+        world: &World,
+        i: usize,
+    ) -> Vec<(Pos, TileKind, Option<String>, Option<usize>)> {
+        level_rect(i)
+            .into_iter()
+            .map(|pos| {
+                let item = world[pos].item.as_ref().map(|item| match item {
+                    Item::Instance(ii) => ii.info.name.clone(),
+                    Item::PendingCraft(a, b) => format!("{} + {}", a.name, b.name),
+                });
+                let mob = world.mobs.get(&pos).map(|mob| mob.kind.0);
+                (pos, world[pos].kind, item, mob)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_level_seed_regenerates_the_same_floor() {
+        use crate::world::tests::area;
+        let areas = [area("cellar"), area("attic"), area("roof")];
+        let mut world = generated(&areas, &[]);
+        let (first, second) = (floor_snapshot(&world, 0), floor_snapshot(&world, 1));
+        regenerate_level(&mut world, 1).unwrap();
+        assert!(floor_snapshot(&world, 1) == second);
+        assert!(floor_snapshot(&world, 0) == first);
+
+        // The floor doesn't depend on the levels generated before it.
+        let mut other_run = generated_with_seed(&areas, &[], 1);
+        assert!(floor_snapshot(&other_run, 0) != first);
+        other_run.level_seeds[1] = world.level_seeds[1];
+        regenerate_level(&mut other_run, 1).unwrap();
+        assert!(floor_snapshot(&other_run, 1) == second);
+    }
+
+    #[test]
+    fn levels_never_start_on_their_stairs() {
+        let mut world = test_world();
+        let stairs = Pos::new(5, 5);
+        let mut lgr = LevelgenResult {
+            start: stairs,
+            end: stairs,
+        };
+        keep_start_off_stairs(&world, 0, &mut lgr);
+        assert_eq!(lgr.end, stairs);
+        assert_eq!((lgr.start - stairs).dist_squared(), 1);
+        assert!(world[lgr.start].kind.is_walkable());
+
+        // With only the stairs to stand on, there's nothing better to pick.
+        for pos in level_rect(0) {
+            world[pos].kind = TileKind::Wall;
+        }
+        world[stairs].kind = TileKind::Floor;
+        let mut lgr = LevelgenResult {
+            start: stairs,
+            end: stairs,
+        };
+        keep_start_off_stairs(&world, 0, &mut lgr);
+        assert_eq!(lgr.start, stairs);
+    }
+
+    #[test]
+    fn starting_at_a_later_level_brings_gear() {
+        use crate::world::tests::area;
+        let mut world = generated(&[area("cellar"), area("attic"), area("roof")], &[]);
+        let start = level_rect(2)
+            .into_iter()
+            .find(|&pos| world[pos].kind.is_walkable())
+            .unwrap();
+        let gear = debug_starting_gear(&world, 2);
+        world.start_at_level(2, start, gear);
+        assert_eq!(world.level_id(), 2);
+        assert_eq!(world.player_pos, start);
+        let mut equipped_kinds = world
+            .inventory
+            .items
+            .iter()
+            .filter(|item| item.equipped)
+            .filter_map(|item| match &item.item {
+                Item::Instance(ii) => Some(ii.info.kind),
+                Item::PendingCraft(..) => None,
+            })
+            .collect::<Vec<_>>();
+        equipped_kinds.sort_by_key(|kind| format!("{kind:?}"));
+        assert_eq!(
+            equipped_kinds,
+            [
+                ItemKind::Armor,
+                ItemKind::MeleeWeapon,
+                ItemKind::RangedWeapon
+            ]
+        );
+    }
+
+    #[test]
+    fn a_run_seed_gives_the_same_dungeon_and_fights() {
+        use crate::world::tests::{area, spawn};
+        let areas = [area("cellar"), area("attic"), area("roof")];
+        let run = |seed| {
+            let mut world = generated_with_seed(&areas, &[], seed);
+            let floors = (0..areas.len())
+                .map(|i| floor_snapshot(&world, i))
+                .collect::<Vec<_>>();
+            world.mobs.clear();
+            let pos = world.player_pos + Offset { x: 1, y: 0 };
+            world[pos].kind = TileKind::Floor;
+            spawn(&mut world, "rat", pos);
+            let damage = (0..10)
+                .map(|_| {
+                    world.tick();
+                    world.player_damage
+                })
+                .collect::<Vec<_>>();
+            (floors, damage)
+        };
+        let (floors, damage) = run(3);
+        assert!(damage.last() > Some(&0));
+        assert!(run(3) == (floors.clone(), damage));
+        assert!(run(4).0 != floors);
+    }
 }
@@ -15,6 +15,27 @@ pub enum ItemKind {
     Food,
 }
 
+/// Where a piece of armor is worn. Only one piece per slot can be equipped.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArmorSlot {
+    Head,
+    Body,
+    Accessory,
+}
+
+impl ArmorSlot {
+    pub const ALL: [ArmorSlot; 3] = [ArmorSlot::Head, ArmorSlot::Body, ArmorSlot::Accessory];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ArmorSlot::Head => "Head",
+            ArmorSlot::Body => "Body",
+            ArmorSlot::Accessory => "Accessory",
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PokemonType {
@@ -142,6 +163,21 @@ impl PokemonType {
         let eff2 = defense2.map(|defense2| attack.get_effectiveness(defense2));
         multiply_effectiveness(eff1, eff2.unwrap_or(One))
     }
+
+    /// Effectiveness against a defender with any number of types, like the
+    /// player in several pieces of armor. Capped between Quarter and Four.
+    pub fn get_effectiveness_all(self, defenses: &[PokemonType]) -> AttackEffectiveness {
+        defenses
+            .iter()
+            .fold(AttackEffectiveness::One, |eff, &defense| {
+                multiply_effectiveness(eff, self.get_effectiveness(defense))
+            })
+    }
+
+    /// Same-type attack bonus: does the attacker share this attack's type?
+    pub fn is_stab(self, attacker_types: &[PokemonType]) -> bool {
+        attacker_types.contains(&self)
+    }
 }
 #[derive(Debug, Clone, Copy)]
 pub enum AttackEffectiveness {
@@ -171,14 +207,24 @@ fn multiply_effectiveness(
     eff2: AttackEffectiveness,
 ) -> AttackEffectiveness {
     use AttackEffectiveness::*;
-    match (eff1, eff2) {
-        (Zero, _) | (_, Zero) => Zero,
-        (Half, Half) => Quarter,
-        (Half, Two) | (Two, Half) => One,
-        (Two, Two) => Four,
-        (eff1, One) => eff1,
-        (One, eff2) => eff2,
-        _ => One,
+    // Powers of two, so multiplying is adding.
+    let exponent = |eff| match eff {
+        Zero => None,
+        Quarter => Some(-2),
+        Half => Some(-1),
+        One => Some(0),
+        Two => Some(1),
+        Four => Some(2),
+    };
+    match (exponent(eff1), exponent(eff2)) {
+        (Some(a), Some(b)) => match (a + b).clamp(-2, 2) {
+            -2 => Quarter,
+            -1 => Half,
+            0 => One,
+            1 => Two,
+            _ => Four,
+        },
+        _ => Zero,
     }
 }
 
@@ -277,6 +323,12 @@ pub struct MonsterDefinition {
     pub death: String,
     pub ranged: bool,
     pub speed: u8,
+    /// Large enough to block line of sight, like a wall.
+    #[serde(default)]
+    pub blocks_sight: bool,
+    /// Picks up items it walks over. Only used if the mobs_loot_items rule is on.
+    #[serde(default)]
+    pub loots: bool,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -303,6 +355,12 @@ pub struct ItemDefinition {
     pub description: String,
     pub kind: ItemKind,
     pub craft_id: Option<CraftId>,
+    /// Placed exactly once per run instead of joining an area's item pool.
+    #[serde(default)]
+    pub unique: bool,
+    /// For armor. Armor without a slot can go in any slot.
+    #[serde(default)]
+    pub slot: Option<ArmorSlot>,
 }
 
 #[derive(Enum, PartialEq, Eq, Hash, Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
@@ -324,6 +382,27 @@ pub struct Area {
     pub melee_weapons: Vec<String>,
     pub ranged_weapons: Vec<String>,
     pub food: Vec<String>,
+    /// Keeps the stairs down from working until the condition is met.
+    #[serde(default)]
+    pub stairs_lock: Option<StairsLock>,
+    /// (weight, monster level) pairs overriding the default spawn mix.
+    #[serde(default)]
+    pub enemy_level_weights: Option<Vec<(u32, usize)>>,
+    /// Name of a monster to place once on this level as a tougher mini-boss.
+    #[serde(default)]
+    pub mini_boss: Option<String>,
+    /// Dark, so the player only sees as far as the light reaches.
+    #[serde(default)]
+    pub unlit: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StairsLock {
+    /// Defeat the monster with this name.
+    Defeat(String),
+    /// Carry the item with this name.
+    Carry(String),
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -385,7 +464,7 @@ where
         let resp = client
             .post(url)
             .json(&input)
-            .timeout(Duration::from_secs(60 * 3))
+            .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
             .send();
         tx.send(resp.map_err(|e| e.to_string()).map(|r| Response {
             status: r.status().as_u16().into(),
@@ -396,6 +475,15 @@ where
     BootlegFuture { rx, state: None }
 }
 
+/// Native requests error out on their own after this many seconds.
+const HTTP_TIMEOUT_SECS: u64 = 60 * 3;
+/// Give up on a request and retry it if the server hasn't answered in this many seconds.
+/// The server answers all at once, so silence this long means it's stuck. Longer than
+/// HTTP_TIMEOUT_SECS, so this only catches requests that would otherwise hang, like on wasm.
+pub const REQUEST_TIMEOUT_SECS: f64 = HTTP_TIMEOUT_SECS as f64 + 20.0;
+/// Stop retrying a setting, areas, monsters, items or boss request after this many failures.
+pub const REQUEST_MAX_ATTEMPTS: usize = 5;
+
 pub fn api_url() -> String {
     std::env::var("SERVER_URL").unwrap_or("https://7drl24.pfe.io".into())
 }
@@ -414,11 +502,26 @@ pub enum Request {
     Boss,
 }
 
+impl Request {
+    /// What this request generates, for status messages.
+    pub fn what(&self) -> &'static str {
+        match self {
+            Request::Setting => "setting",
+            Request::Areas => "areas",
+            Request::Monsters(_) => "monsters",
+            Request::Items(_) => "items",
+            Request::Craft { .. } => "crafted item",
+            Request::Boss => "boss",
+        }
+    }
+}
+
 impl Request {}
 
 pub struct PendingRequest {
     req: Request,
     fut: BootlegFuture<Result<Response, String>>,
+    sent_at: f64,
 }
 
 enum RequestResult {
@@ -429,6 +532,7 @@ enum RequestResult {
     Craft(ItemDefinition),
     Boss(BossDefinition),
     Pending,
+    TimedOut,
     Error(String),
 }
 
@@ -438,11 +542,21 @@ struct ServerError {
 }
 
 impl PendingRequest {
+    /// What a request still unanswered after `elapsed` seconds amounts to.
+    fn unanswered(elapsed: f64) -> RequestResult {
+        if elapsed >= REQUEST_TIMEOUT_SECS {
+            RequestResult::TimedOut
+        } else {
+            RequestResult::Pending
+        }
+    }
+
     fn get(&mut self) -> RequestResult {
         use RequestResult::*;
+        let elapsed = macroquad::time::get_time() - self.sent_at;
         let resp = self.fut.get();
         match resp {
-            None => Pending,
+            None => Self::unanswered(elapsed),
             Some(Err(s)) => Error(s.clone()),
             Some(Ok(resp)) => {
                 macroquad::miniquad::info!("{:?}", resp);
@@ -482,10 +596,45 @@ impl PendingRequest {
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
 pub struct CraftId(usize);
 
+/// Give up on generating a recipe after this many failed attempts.
+pub const CRAFT_MAX_ATTEMPTS: usize = 3;
+
+/// Where a craft the player is waiting on stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CraftStatus {
+    /// Not sent to the server yet.
+    Queued,
+    Generating,
+    /// The server failed this many times and we're trying again.
+    Retrying(usize),
+    /// Gave up after CRAFT_MAX_ATTEMPTS failures.
+    Failed,
+}
+
+impl CraftStatus {
+    pub fn describe(&self) -> String {
+        match self {
+            CraftStatus::Queued => "Crafting queued...".into(),
+            CraftStatus::Generating => "Crafting in progress...".into(),
+            CraftStatus::Retrying(n) => format!("Crafting in progress (retry {n})..."),
+            CraftStatus::Failed => "Crafting failed".into(),
+        }
+    }
+}
+
 pub enum IgState {
     Generating(&'static str),
     Idle,
-    Error { msg: String, count: usize },
+    /// `what` is the kind of content the last failed request was generating.
+    TimedOut {
+        what: &'static str,
+        count: usize,
+    },
+    Error {
+        what: &'static str,
+        msg: String,
+        count: usize,
+    },
 }
 
 /// Contains raw AI-generated content fetched from the server.
@@ -503,6 +652,23 @@ pub struct IdeaGuy {
     pub next_craft_id: CraftId,
     pub error: Option<String>,
     pub error_count: usize,
+    /// Whether the most recent error was the server not responding at all.
+    pub timed_out: bool,
+    /// What the most recent failed request was generating.
+    pub failing: &'static str,
+    /// Whether we gave up on the server and are playing pre-generated content.
+    pub offline: bool,
+    /// Failed attempts per recipe, keyed like `recipes`.
+    craft_failures: HashMap<(usize, usize), usize>,
+    /// Failed attempts in a row per kind of non-craft request, keyed by `Request::what`.
+    request_failures: HashMap<&'static str, usize>,
+    /// Whether a non-craft request failed too often and won't be sent again.
+    pub gave_up: bool,
+}
+
+/// The pre-generated boss, for when there's no generated one to fight.
+pub fn fallback_boss() -> BossDefinition {
+    serde_json::from_str(include_str!("../server/data/hk_boss.json")).unwrap()
 }
 
 impl IdeaGuy {
@@ -521,14 +687,78 @@ impl IdeaGuy {
             next_craft_id: CraftId(0),
             error: None,
             error_count: 0,
+            timed_out: false,
+            failing: "",
+            offline: false,
+            craft_failures: HashMap::new(),
+            request_failures: HashMap::new(),
+            gave_up: false,
         };
         slf.request(Request::Setting);
         slf
     }
 
+    /// Stop talking to the server and fill in everything with the same
+    /// pre-generated world the server uses for its "pregen" theme.
+    pub fn go_offline(&mut self) {
+        macroquad::miniquad::info!("Falling back to pre-generated content");
+        self.outgoing.clear();
+        self.error = None;
+        self.error_count = 0;
+        self.timed_out = false;
+        self.gave_up = false;
+        self.offline = true;
+        self.setting = Some(include_str!("../server/data/hk.txt").trim().into());
+        self.areas =
+            Some(serde_json::from_str(include_str!("../server/data/hk_areas.json")).unwrap());
+        self.monsters =
+            Some(serde_json::from_str(include_str!("../server/data/hk_monsters.json")).unwrap());
+        self.items =
+            Some(serde_json::from_str(include_str!("../server/data/hk_items.json")).unwrap());
+        self.boss = Some(fallback_boss());
+    }
+
+    /// Make up a crafting result without the server.
+    fn craft_offline(&mut self, item1: usize, item2: usize, craft_id: CraftId) {
+        let items = self.items.as_mut().unwrap();
+        let (a, b) = (&items[item1], &items[item2]);
+        let base_name = a.name.split(" +").next().unwrap_or(&a.name);
+        let level = a.level + 1;
+        let item = ItemDefinition {
+            name: format!("{} +{}", base_name, level - 1),
+            level,
+            ty: a.ty,
+            description: format!("{} reworked with {}.", a.name, b.name),
+            kind: a.kind,
+            craft_id: Some(craft_id),
+            unique: false,
+            slot: a.slot,
+        };
+        // Crafting the same thing twice gives the same result.
+        let result = match items.iter().position(|i| i.name == item.name) {
+            Some(i) => i,
+            None => {
+                items.push(item);
+                items.len() - 1
+            }
+        };
+        self.recipes.insert((item1, item2), result);
+    }
+
     pub fn craft(&mut self, item1: usize, item2: usize) {
+        // Don't ask for the same recipe twice.
+        let already_queued = self.outgoing.iter().any(|r| {
+            matches!(r.req, Request::Craft { item1: i1, item2: i2, .. } if (i1, i2) == (item1, item2))
+        });
+        if already_queued {
+            return;
+        }
         let craft_id = self.next_craft_id;
         self.next_craft_id = CraftId(self.next_craft_id.0 + 1);
+        if self.offline {
+            self.craft_offline(item1, item2, craft_id);
+            return;
+        }
         self.request(Request::Craft {
             item1,
             item2,
@@ -536,6 +766,55 @@ impl IdeaGuy {
         });
     }
 
+    pub fn get_craft_status(&self, item1: usize, item2: usize) -> Option<CraftStatus> {
+        let failures = self
+            .craft_failures
+            .get(&(item1, item2))
+            .copied()
+            .unwrap_or(0);
+        let in_flight = self.outgoing.iter().any(|r| {
+            matches!(r.req, Request::Craft { item1: i1, item2: i2, .. } if (i1, i2) == (item1, item2))
+        });
+        if failures >= CRAFT_MAX_ATTEMPTS {
+            Some(CraftStatus::Failed)
+        } else if !in_flight {
+            None
+        } else if failures > 0 {
+            Some(CraftStatus::Retrying(failures))
+        } else {
+            Some(CraftStatus::Generating)
+        }
+    }
+
+    /// Whether to send a failed request again. Everything gives up after a few tries.
+    fn should_retry(&mut self, req: &Request) -> bool {
+        let Request::Craft { item1, item2, .. } = *req else {
+            let failures = self.request_failures.entry(req.what()).or_default();
+            *failures += 1;
+            if *failures >= REQUEST_MAX_ATTEMPTS {
+                macroquad::miniquad::error!("{}", format!("Giving up on the {}", req.what()));
+                self.gave_up = true;
+                return false;
+            }
+            return true;
+        };
+        let failures = self.craft_failures.entry((item1, item2)).or_default();
+        *failures += 1;
+        if *failures >= CRAFT_MAX_ATTEMPTS {
+            macroquad::miniquad::error!("{}", format!("Giving up on crafting {item1} + {item2}"));
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn num_pending_crafts(&self) -> usize {
+        self.outgoing
+            .iter()
+            .filter(|r| matches!(r.req, Request::Craft { .. }))
+            .count()
+    }
+
     fn request_inner(&mut self, req: Request) -> PendingRequest {
         macroquad::miniquad::info!("Requesting {:?}", req);
         let api_url = api_url();
@@ -585,7 +864,11 @@ impl IdeaGuy {
                 },
             ),
         };
-        PendingRequest { req, fut }
+        PendingRequest {
+            req,
+            fut,
+            sent_at: macroquad::time::get_time(),
+        }
     }
 
     pub fn request(&mut self, req: Request) {
@@ -638,17 +921,35 @@ impl IdeaGuy {
         let mut queue = self.outgoing.drain(..).rev().collect::<Vec<_>>();
         while let Some(mut req) = queue.pop() {
             let result = req.get();
-            if !matches!(result, RequestResult::Error(_) | RequestResult::Pending) {
+            if !matches!(
+                result,
+                RequestResult::Error(_) | RequestResult::TimedOut | RequestResult::Pending
+            ) {
                 self.error = None;
                 self.error_count = 0;
+                self.timed_out = false;
+                self.request_failures.remove(req.req.what());
             }
             match result {
                 RequestResult::Error(e) => {
                     macroquad::miniquad::error!("{}", e);
                     self.error = Some(e);
                     self.error_count += 1;
-                    // Retry
-                    self.request(req.req);
+                    self.timed_out = false;
+                    self.failing = req.req.what();
+                    if self.should_retry(&req.req) {
+                        self.request(req.req);
+                    }
+                }
+                RequestResult::TimedOut => {
+                    macroquad::miniquad::error!("{:?} timed out", req.req);
+                    self.error = Some("Server timed out".into());
+                    self.error_count += 1;
+                    self.timed_out = true;
+                    self.failing = req.req.what();
+                    if self.should_retry(&req.req) {
+                        self.request(req.req);
+                    }
                 }
                 RequestResult::Pending => {
                     self.outgoing.push(req);
@@ -708,8 +1009,14 @@ impl IdeaGuy {
         }
     }
     pub fn get_state(&self) -> IgState {
-        if let Some(err) = self.error.as_ref() {
+        if self.error.is_some() && self.timed_out {
+            IgState::TimedOut {
+                what: self.failing,
+                count: self.error_count,
+            }
+        } else if let Some(err) = self.error.as_ref() {
             IgState::Error {
+                what: self.failing,
                 msg: err.clone(),
                 count: self.error_count,
             }
@@ -730,3 +1037,131 @@ impl IdeaGuy {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An IdeaGuy that hasn't sent anything, so tests don't touch the network.
+    pub(crate) fn idle_guy() -> IdeaGuy {
+        IdeaGuy {
+            theme: "test".into(),
+            api_url: String::new(),
+            setting: None,
+            areas: None,
+            monsters: None,
+            items: None,
+            boss: None,
+            outgoing: vec![],
+            recipes: HashMap::new(),
+            next_craft_id: CraftId(0),
+            error: None,
+            error_count: 0,
+            timed_out: false,
+            failing: "",
+            offline: false,
+            craft_failures: HashMap::new(),
+            request_failures: HashMap::new(),
+            gave_up: false,
+        }
+    }
+
+    /// A request that never gets an answer.
+    fn in_flight(req: Request) -> PendingRequest {
+        let (_tx, rx) = mpsc::channel();
+        PendingRequest {
+            req,
+            fut: BootlegFuture { rx, state: None },
+            sent_at: 0.0,
+        }
+    }
+
+    #[test]
+    fn crafting_a_queued_pair_again_is_skipped() {
+        let mut ig = idle_guy();
+        ig.outgoing.push(in_flight(Request::Craft {
+            item1: 1,
+            item2: 2,
+            craft_id: CraftId(0),
+        }));
+        ig.next_craft_id = CraftId(1);
+        ig.craft(1, 2);
+        assert_eq!(ig.num_pending_crafts(), 1);
+        assert_eq!(ig.next_craft_id.0, 1);
+    }
+
+    #[test]
+    fn offline_content_has_everything_the_areas_need() {
+        let mut ig = idle_guy();
+        ig.error = Some("loop".into());
+        ig.go_offline();
+        assert!(ig.get_missing_monster_names().is_empty());
+        assert!(ig.get_missing_item_names().is_empty());
+        assert!(matches!(ig.get_state(), IgState::Idle));
+    }
+
+    #[test]
+    fn unanswered_requests_time_out_after_the_limit() {
+        assert!(matches!(
+            PendingRequest::unanswered(0.0),
+            RequestResult::Pending
+        ));
+        assert!(matches!(
+            PendingRequest::unanswered(HTTP_TIMEOUT_SECS as f64),
+            RequestResult::Pending
+        ));
+        assert!(matches!(
+            PendingRequest::unanswered(REQUEST_TIMEOUT_SECS),
+            RequestResult::TimedOut
+        ));
+    }
+
+    #[test]
+    fn effectiveness_against_many_types_multiplies() {
+        use PokemonType::*;
+        let scale = |attack: PokemonType, defenses: &[PokemonType]| {
+            attack.get_effectiveness_all(defenses).get_scale()
+        };
+        assert_eq!(scale(Fire, &[]), AttackEffectiveness::One.get_scale());
+        assert_eq!(
+            scale(Fire, &[Grass, Grass]),
+            AttackEffectiveness::Four.get_scale()
+        );
+        assert_eq!(
+            scale(Fire, &[Grass, Water]),
+            AttackEffectiveness::One.get_scale()
+        );
+        // Capped at Four, and one immunity cancels everything else.
+        assert_eq!(
+            scale(Fire, &[Grass, Grass, Grass]),
+            AttackEffectiveness::Four.get_scale()
+        );
+        assert_eq!(scale(Normal, &[Rock, Rock, Ghost]), 0);
+    }
+
+    #[test]
+    fn crafts_report_retries_then_fail() {
+        let mut ig = idle_guy();
+        let craft = Request::Craft {
+            item1: 1,
+            item2: 2,
+            craft_id: CraftId(0),
+        };
+        assert_eq!(ig.get_craft_status(1, 2), None);
+        ig.outgoing.push(in_flight(craft.clone()));
+        assert_eq!(ig.get_craft_status(1, 2), Some(CraftStatus::Generating));
+        for failures in 1..CRAFT_MAX_ATTEMPTS {
+            assert!(ig.should_retry(&craft));
+            assert_eq!(
+                ig.get_craft_status(1, 2),
+                Some(CraftStatus::Retrying(failures))
+            );
+        }
+        assert!(!ig.should_retry(&craft));
+        ig.outgoing.clear();
+        assert_eq!(ig.get_craft_status(1, 2), Some(CraftStatus::Failed));
+        assert_eq!(CraftStatus::Failed.describe(), "Crafting failed");
+        // Other requests always retry.
+        assert!(ig.should_retry(&Request::Setting));
+    }
+}
@@ -5,10 +5,11 @@ use macroquad::prelude::*;
 use macroquad::text::Font;
 use noise::{NoiseFn, Perlin};
 use rand_distr::{Distribution, Normal};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use crate::net::{Color, ItemKind};
-use crate::world::{Item, MobKindInfo};
+use crate::grid::{Offset, EAST, NORTH, SOUTH, WEST};
+use crate::net::{AttackEffectiveness, Color, ItemKind, PokemonType};
+use crate::world::{Item, MobKindInfo, PlayerAction};
 use crate::{grid::Pos, grid::Rect, world::TileKind};
 
 pub const FOV_BG: macroquad::color::Color = DARKGRAY;
@@ -23,6 +24,61 @@ pub struct ShotAnimation {
 #[derive(Clone, Debug)]
 pub enum Animation {
     Shot(ShotAnimation),
+    /// A fading line showing where a mob is about to fire.
+    Aim(ShotAnimation),
+    /// Fades the map in from black after changing levels.
+    LevelTransition,
+}
+
+/// How long the fade after taking the stairs lasts. Input is ignored meanwhile.
+pub const LEVEL_TRANSITION_DURATION: f32 = 0.6;
+/// Share of the screen width the side panel gets by default, and how far the player can move it.
+const SIDEBAR_FRACTION: f32 = 0.25;
+const SIDEBAR_FRACTION_RANGE: std::ops::RangeInclusive<f32> = 0.15..=0.45;
+
+/// Whether any of these animations is the fade between levels.
+pub fn plays_level_transition(animations: &[AnimationState]) -> bool {
+    animations
+        .iter()
+        .any(|a| matches!(a.animation, Animation::LevelTransition))
+}
+
+/// The map's square on screen, left of a side panel `right_offset` wide and above
+/// a bar `bottom_offset` tall, as (x, y, size). The size includes a 10px margin.
+fn map_square(screen: (f32, f32), right_offset: f32, bottom_offset: f32) -> (f32, f32, f32) {
+    let width = screen.0 - right_offset;
+    let height = screen.1 - bottom_offset;
+    let game_size = width.min(height);
+    let offset_x = (screen.0 - game_size - right_offset) / 2. + 10.;
+    let offset_y = (screen.1 - game_size) / 2. + 10.;
+    (offset_x, offset_y, game_size)
+}
+
+/// Where in a grid cell's square to draw, given the grid's top-left corner on
+/// screen and the square size. `off` is how far into the square, in squares.
+fn cell_to_screen(
+    cell: (i32, i32),
+    origin: (f32, f32),
+    sq_size: f32,
+    off: (f32, f32),
+) -> (f32, f32) {
+    (
+        origin.0 + sq_size * (cell.0 as f32 + off.0),
+        origin.1 + sq_size * (cell.1 as f32 + off.1),
+    )
+}
+
+/// The inverse of `cell_to_screen`: the grid cell at this screen position, if any.
+fn screen_to_cell(
+    screen: (f32, f32),
+    origin: (f32, f32),
+    sq_size: f32,
+    grid_size: usize,
+) -> Option<(i32, i32)> {
+    let x = ((screen.0 - origin.0) / sq_size).floor() as i32;
+    let y = ((screen.1 - origin.1) / sq_size).floor() as i32;
+    let in_grid = |c: i32| c >= 0 && c < grid_size as i32;
+    (in_grid(x) && in_grid(y)).then_some((x, y))
 }
 
 #[derive(Clone, Debug)]
@@ -48,6 +104,50 @@ pub enum UiButton {
     Drop,
     Craft,
     Inspect,
+    Move(Offset),
+    Wait,
+    PickUp,
+    /// Debug builds only: generates the current floor again from its seed.
+    RegenerateFloor,
+}
+
+impl UiButton {
+    /// The player action for an on-screen control, the same one its key would do.
+    pub fn player_action(self, fire: bool) -> Option<PlayerAction> {
+        match self {
+            UiButton::Move(dir) if fire => Some(PlayerAction::Fire(dir)),
+            UiButton::Move(dir) => Some(PlayerAction::Move(dir)),
+            UiButton::Wait => Some(PlayerAction::Wait),
+            UiButton::PickUp => Some(PlayerAction::PickUp),
+            UiButton::Equip
+            | UiButton::Drop
+            | UiButton::Craft
+            | UiButton::Inspect
+            | UiButton::RegenerateFloor => None,
+        }
+    }
+}
+
+/// A checkbox to turn key repeat on or off, and sliders for its timing while on.
+fn key_repeat_setting(ui: &mut egui::Ui, label: &str, repeat: &mut Option<crate::KeyRepeat>) {
+    let mut enabled = repeat.is_some();
+    if ui.checkbox(&mut enabled, label).changed() {
+        *repeat = enabled.then_some(crate::KeyRepeat::DEFAULT);
+    }
+    if let Some(repeat) = repeat {
+        ui.horizontal(|ui| {
+            ui.label("Delay:");
+            ui.add(egui::Slider::new(&mut repeat.initial, 0.1..=1.0).suffix("s"));
+            ui.label("Rate:");
+            let mut rate = 1.0 / repeat.delay;
+            if ui
+                .add(egui::Slider::new(&mut rate, 2.0..=60.0).suffix("/s"))
+                .changed()
+            {
+                repeat.delay = 1.0 / rate;
+            }
+        });
+    }
 }
 
 pub struct Ui {
@@ -55,11 +155,33 @@ pub struct Ui {
     font: Font,
     pub ui_selected: bool,
     pub help_selected: bool,
+    pub recipes_selected: bool,
+    pub description_selected: bool,
+    pub settings_selected: bool,
+    last_description: Vec<String>,
+    pub sound_enabled: bool,
+    pub touch_controls: bool,
+    /// Whether on-screen direction buttons fire instead of moving.
+    pub touch_fire: bool,
+    /// Held while moving to fire instead.
+    pub fire_modifier: crate::FireModifier,
+    /// Copied onto the world every frame.
+    pub rules: crate::world::Rules,
+    /// Repeat for held direction keys, or None to not repeat them.
+    pub move_key_repeat: Option<crate::KeyRepeat>,
+    /// Like move_key_repeat, but while the fire modifier is held.
+    pub fire_key_repeat: Option<crate::KeyRepeat>,
     camera_delta: Option<(f32, f32)>,
     last_upper_left: Option<Pos>,
+    /// Map position under the mouse, if any.
+    hovered_pos: Option<Pos>,
     pub inventory_selected: HashSet<usize>,
     pub user_scale_factor: f32,
     tmp_scale_factor: f32,
+    /// Share of the screen width given to the side panel.
+    sidebar_fraction: f32,
+    /// When the run began, from macroquad's get_time.
+    run_started_at: f64,
     animations: Vec<AnimationState>,
 
     pub ui_button: Option<UiButton>,
@@ -94,8 +216,9 @@ fn condition_color(condition: ItemCondition) -> Color {
     }
 }
 
-fn get_item_condition(durability: usize) -> ItemCondition {
-    match durability {
+/// Buckets durability by how much of the item's max is left, in twentieths.
+fn get_item_condition(durability: usize, max_durability: usize) -> ItemCondition {
+    match durability * 20 / max_durability.max(1) {
         0..=2 => ItemCondition::Poor,
         3..=6 => ItemCondition::Acceptable,
         7..=10 => ItemCondition::Good,
@@ -105,6 +228,165 @@ fn get_item_condition(durability: usize) -> ItemCondition {
     }
 }
 
+/// How a mob fares against the player's weapon, from the player's point of view.
+fn effectiveness_label(eff: AttackEffectiveness) -> (&'static str, Color) {
+    match eff {
+        AttackEffectiveness::Zero => ("Immune", Color::Gray),
+        AttackEffectiveness::Quarter | AttackEffectiveness::Half => ("Resist", Color::Red),
+        AttackEffectiveness::One => ("Neutral", Color::White),
+        AttackEffectiveness::Two | AttackEffectiveness::Four => ("Weak", Color::Gold),
+    }
+}
+
+fn tile_name(kind: TileKind) -> &'static str {
+    match kind {
+        TileKind::Floor | TileKind::YellowFloor => "Floor",
+        TileKind::Wall | TileKind::YellowWall => "Wall",
+        TileKind::BloodyFloor => "Bloodstained floor",
+        TileKind::Stairs => "Stairs down",
+        TileKind::Torch => "Torch",
+        TileKind::Hazard => "Hazard",
+    }
+}
+
+/// Whether the bundled DejaVu Sans Mono can draw `c` on its own. It covers most alphabets
+/// and symbol blocks below CJK, but no emoji, and combining or formatting marks draw nothing.
+fn is_renderable(c: char) -> bool {
+    if c.is_control() || c.is_whitespace() {
+        return false;
+    }
+    match c as u32 {
+        // Combining marks.
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x20D0..=0x20FF => false,
+        // Zero-width and bidi formatting characters.
+        0x200B..=0x200F | 0x2028..=0x202F | 0x2060..=0x206F => false,
+        code => code < 0x3000,
+    }
+}
+
+/// Picks the glyph for a monster: the first drawable character of its char, then of its name.
+fn mob_glyph(char: &str, name: &str) -> char {
+    char.chars()
+        .find(|&c| is_renderable(c))
+        .or_else(|| {
+            name.chars()
+                .find(|c| c.is_alphanumeric() && is_renderable(*c))
+        })
+        .unwrap_or('?')
+}
+
+fn tile_glyph(kind: TileKind) -> (char, macroquad::color::Color) {
+    match kind {
+        TileKind::Floor => ('.', LIGHTGRAY),
+        TileKind::Wall => ('#', WHITE),
+        TileKind::YellowFloor => ('.', YELLOW),
+        TileKind::YellowWall => ('#', YELLOW),
+        TileKind::BloodyFloor => ('.', RED),
+        TileKind::Stairs => ('>', LIGHTGRAY),
+        TileKind::Torch => ('*', ORANGE),
+        TileKind::Hazard => ('^', RED),
+    }
+}
+
+fn item_glyph(item: &Item) -> (char, macroquad::color::Color) {
+    match item {
+        Item::PendingCraft(..) => ('?', PINK),
+        Item::Instance(ii) => {
+            let char = match ii.info.kind {
+                ItemKind::MeleeWeapon => ')',
+                ItemKind::RangedWeapon => '/',
+                ItemKind::Armor => '[',
+                ItemKind::Food => '%',
+            };
+            (char, ii.info.ty.get_color().into())
+        }
+    }
+}
+
+/// Most remembered tiles the map export will walk, in case memory is somehow huge.
+const MAP_EXPORT_MAX_TILES: usize = 100_000;
+
+/// The remembered map around the player as plain text, cropped to what's been
+/// explored. Other levels are left out: they never touch this one.
+pub fn export_map_text(sim: &crate::world::World, memory: &crate::world::Memory) -> String {
+    let player_pos = sim.get_player_pos();
+    // Flood out through remembered tiles. Walls are remembered too, so this stays on this level.
+    let mut explored = HashSet::from([player_pos]);
+    let mut frontier = vec![player_pos];
+    while let Some(pos) = frontier.pop() {
+        for offset in crate::grid::DIRECTIONS {
+            let next = pos + offset;
+            if explored.len() < MAP_EXPORT_MAX_TILES
+                && memory.tile_map[next].is_some()
+                && explored.insert(next)
+            {
+                frontier.push(next);
+            }
+        }
+    }
+    let (x1, x2) = (
+        explored.iter().map(|p| p.x).min().unwrap(),
+        explored.iter().map(|p| p.x).max().unwrap(),
+    );
+    let (y1, y2) = (
+        explored.iter().map(|p| p.y).min().unwrap(),
+        explored.iter().map(|p| p.y).max().unwrap(),
+    );
+    let mut text = String::new();
+    for y in y1..=y2 {
+        let mut line = String::new();
+        for x in x1..=x2 {
+            let pos = Pos::new(x, y);
+            let tile = memory.tile_map[pos]
+                .as_ref()
+                .filter(|_| explored.contains(&pos));
+            line.push(if pos == player_pos {
+                '@'
+            } else if let Some(mob) = memory.mobs.get(&pos) {
+                let mki = sim.get_mobkind_info(mob.kind);
+                mob_glyph(&mki.char, &mki.name)
+            } else if let Some(tile) = tile {
+                match tile.item {
+                    Some(ref item) => item_glyph(item).0,
+                    None => tile_glyph(tile.kind).0,
+                }
+            } else {
+                ' '
+            });
+        }
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+/// What crafting two inventory items together would make, as far as is known.
+#[derive(Debug, PartialEq)]
+enum CraftPreview {
+    CantCombine,
+    Known(std::rc::Rc<crate::world::ItemInfo>),
+    /// The recipe hasn't been generated yet.
+    Unknown,
+}
+
+/// Previews crafting inventory items `a` and `b`, in either order. None if
+/// either isn't a finished item.
+fn craft_preview(sim: &crate::world::World, a: usize, b: usize) -> Option<CraftPreview> {
+    let items = &sim.inventory.items;
+    let (Some(Item::Instance(a)), Some(Item::Instance(b))) =
+        (items.get(a).map(|x| &x.item), items.get(b).map(|x| &x.item))
+    else {
+        return None;
+    };
+    if a.info.level.abs_diff(b.info.level) > sim.rules.max_craft_level_diff() {
+        return Some(CraftPreview::CantCombine);
+    }
+    Some(match sim.world_info.get_recipe_result(&a.info, &b.info) {
+        Some(result) => CraftPreview::Known(result),
+        None => CraftPreview::Unknown,
+    })
+}
+
 fn to_egui(c: &Color) -> egui::Color32 {
     let color = macroquad::color::Color::from(*c);
     let [r, g, b, _a] = color.into();
@@ -121,15 +403,29 @@ fn normpdf(x: f32, mean: f32, std: f32) -> f32 {
 impl Ui {
     pub fn new(grid_size: Option<usize>, font: Font) -> Ui {
         Ui {
-            grid_size: grid_size.unwrap_or(32),
+            grid_size: grid_size.unwrap_or(crate::world::VIEW_SIZE as usize),
             font,
             ui_selected: false,
             help_selected: false,
+            recipes_selected: false,
+            description_selected: false,
+            settings_selected: false,
+            last_description: Vec::new(),
+            sound_enabled: true,
+            touch_controls: cfg!(target_family = "wasm"),
+            touch_fire: false,
+            fire_modifier: crate::FireModifier::Shift,
+            rules: crate::world::Rules::default(),
+            move_key_repeat: crate::MOVE_KEY_REPEAT,
+            fire_key_repeat: crate::FIRE_KEY_REPEAT,
             camera_delta: None,
             last_upper_left: None,
+            hovered_pos: None,
             inventory_selected: HashSet::new(),
             user_scale_factor: 1.0,
             tmp_scale_factor: 1.0,
+            sidebar_fraction: SIDEBAR_FRACTION,
+            run_started_at: macroquad::time::get_time(),
             animations: Vec::new(),
             ui_button: None,
         }
@@ -139,6 +435,10 @@ impl Ui {
         self.animations.push(animation);
     }
 
+    pub fn in_level_transition(&self) -> bool {
+        plays_level_transition(&self.animations)
+    }
+
     pub fn toggle_ui(&mut self) {
         self.ui_selected = !self.ui_selected;
     }
@@ -147,6 +447,18 @@ impl Ui {
         self.help_selected = !self.help_selected;
     }
 
+    pub fn toggle_recipes(&mut self) {
+        self.recipes_selected = !self.recipes_selected;
+    }
+
+    pub fn toggle_description(&mut self) {
+        self.description_selected = !self.description_selected;
+    }
+
+    pub fn toggle_settings(&mut self) {
+        self.settings_selected = !self.settings_selected;
+    }
+
     fn toggle_row_selection(&mut self, row_index: usize, row_response: &egui::Response) {
         if row_response.clicked() {
             if self.inventory_selected.contains(&row_index) {
@@ -193,7 +505,11 @@ impl Ui {
                             ui.label(job);
                         };
                         basic_label("hjkl or arrows", "Movement");
-                        basic_label("SHIFT + move", "Fire weapon");
+                        basic_label(
+                            &format!("{} + move", self.fire_modifier.name()),
+                            "Fire weapon",
+                        );
+                        basic_label("f then move", "Fire weapon");
                         basic_label("i", "Show inventory.");
                         basic_label(".", "Wait a turn.");
                         basic_label(",", "Pick up item.");
@@ -201,7 +517,11 @@ impl Ui {
                         basic_label("e", "Equip/eat selected item(s).");
                         basic_label("d", "Drop selected item(s).");
                         basic_label("c", "Combine/cook selected item(s).");
-                        basic_label("; or /", "Inspect selected item(s).");
+                        basic_label("; or /", "Inspect selected item(s), or what's nearby.");
+                        basic_label("r", "Show discovered recipes.");
+                        basic_label("v", "Describe surroundings as text.");
+                        basic_label("x", "Copy the explored map as text.");
+                        basic_label("o", "Change how the inventory is ordered.");
                         basic_label("q or ?", "Request help.");
                         ui.separator();
                         ui.label("Click on 'details' in the upper right panel to get more info about that monster.");
@@ -209,6 +529,158 @@ impl Ui {
             });
     }
 
+    fn render_description(
+        &mut self,
+        egui_ctx: &egui::Context,
+        sim: &crate::world::World,
+        memory: &crate::world::Memory,
+    ) {
+        let description = sim.describe_view(memory);
+        if description != self.last_description {
+            // Browser screen readers can follow the console.
+            #[cfg(target_family = "wasm")]
+            miniquad::info!("{}", description.join("\n"));
+            self.last_description = description;
+        }
+        egui::Window::new("Description")
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::LEFT_TOP, egui::Vec2::new(10.0, 10.0))
+            .show(egui_ctx, |ui| {
+                for line in &self.last_description {
+                    ui.label(RichText::new(line).font(self.get_base_font()));
+                }
+            });
+    }
+
+    fn render_touch_controls(&mut self, egui_ctx: &egui::Context) {
+        let button_size = egui::Vec2::splat(48.0 * self.scale_factor());
+        egui::Window::new("Controls")
+            .resizable(false)
+            .collapsible(false)
+            .title_bar(false)
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::Vec2::new(-10.0, -50.0))
+            .show(egui_ctx, |ui| {
+                let font = self.get_base_font();
+                let button = |ui: &mut egui::Ui, text: &str| {
+                    ui.add_sized(
+                        button_size,
+                        egui::Button::new(RichText::new(text).font(font.clone())),
+                    )
+                    .clicked()
+                };
+                egui::Grid::new("touch_dpad").show(ui, |ui| {
+                    ui.label("");
+                    if button(ui, "^") {
+                        self.ui_button = Some(UiButton::Move(NORTH));
+                    }
+                    ui.label("");
+                    ui.end_row();
+                    if button(ui, "<") {
+                        self.ui_button = Some(UiButton::Move(WEST));
+                    }
+                    if button(ui, ".") {
+                        self.ui_button = Some(UiButton::Wait);
+                    }
+                    if button(ui, ">") {
+                        self.ui_button = Some(UiButton::Move(EAST));
+                    }
+                    ui.end_row();
+                    ui.label("");
+                    if button(ui, "v") {
+                        self.ui_button = Some(UiButton::Move(SOUTH));
+                    }
+                    ui.label("");
+                    ui.end_row();
+                });
+                ui.horizontal(|ui| {
+                    ui.toggle_value(&mut self.touch_fire, "Fire");
+                    if ui.button("Pick up").clicked() {
+                        self.ui_button = Some(UiButton::PickUp);
+                    }
+                    if ui.button("Inventory").clicked() {
+                        self.toggle_ui();
+                    }
+                });
+            });
+    }
+
+    fn render_settings(&mut self, egui_ctx: &egui::Context) {
+        egui::Window::new("Settings")
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::new(0.0, 0.0))
+            .show(egui_ctx, |ui| {
+                ui.style_mut().override_text_style = Some(egui::TextStyle::Body);
+                ui.horizontal(|ui| {
+                    ui.label("Fire modifier:");
+                    for modifier in crate::FireModifier::ALL {
+                        ui.selectable_value(&mut self.fire_modifier, modifier, modifier.name());
+                    }
+                });
+                ui.checkbox(
+                    &mut self.rules.recipe_book_shows_all,
+                    "Recipe book lists undiscovered recipes (easy)",
+                );
+                ui.checkbox(
+                    &mut self.rules.mobs_loot_items,
+                    "Some monsters pick up items",
+                );
+                ui.checkbox(
+                    &mut self.rules.craft_adjacent_levels,
+                    "Craft items one level apart",
+                );
+                ui.checkbox(&mut self.rules.stab, "Same-type attack bonus");
+                ui.checkbox(
+                    &mut self.rules.free_roam_after_victory,
+                    "Keep exploring after winning",
+                );
+                ui.separator();
+                key_repeat_setting(ui, "Repeat held movement keys", &mut self.move_key_repeat);
+                key_repeat_setting(ui, "Repeat held fire keys", &mut self.fire_key_repeat);
+                if ui.button("Close").clicked() {
+                    self.toggle_settings();
+                }
+            });
+    }
+
+    fn render_recipes(&mut self, egui_ctx: &egui::Context, sim: &crate::world::World) {
+        egui::Window::new("Recipes")
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 0.0))
+            .show(egui_ctx, |ui| {
+                ui.style_mut().override_text_style = Some(egui::TextStyle::Body);
+                let recipes = sim.get_recipe_book();
+                if recipes.is_empty() {
+                    ui.label("You haven't crafted anything yet.");
+                }
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (a, b, c) in recipes.iter() {
+                        let mut job = egui::text::LayoutJob::default();
+                        for (text, color) in [
+                            (a.name.as_str(), a.ty.get_color()),
+                            (" + ", Color::White),
+                            (b.name.as_str(), b.ty.get_color()),
+                            (" = ", Color::White),
+                            (c.name.as_str(), c.ty.get_color()),
+                        ] {
+                            job.append(
+                                text,
+                                0.0,
+                                egui::TextFormat {
+                                    font_id: self.get_base_font(),
+                                    color: to_egui(&color),
+                                    ..Default::default()
+                                },
+                            );
+                        }
+                        ui.label(job);
+                    }
+                });
+            });
+    }
+
     fn render_inventory(&mut self, egui_ctx: &egui::Context, sim: &crate::world::World) {
         egui::Window::new("Inventory")
             .resizable(false)
@@ -265,10 +737,11 @@ impl Ui {
                                 let display_equipped;
                                 let level;
                                 let cond;
+                                let mut cond_fraction = 1.0;
                                 let mut types = vec![];
                                 match &slot.item {
-                                    Item::PendingCraft(..) => {
-                                        name = "Crafting in progress...".into();
+                                    Item::PendingCraft(a, b) => {
+                                        name = sim.get_craft_status(a, b).describe();
                                         display_slot = "";
                                         display_equipped = "";
                                         level = "".into();
@@ -287,7 +760,9 @@ impl Ui {
                                         display_slot = match item.info.kind {
                                             ItemKind::MeleeWeapon => "Melee",
                                             ItemKind::RangedWeapon => "Ranged",
-                                            ItemKind::Armor => "Equipment",
+                                            ItemKind::Armor => {
+                                                item.info.slot.map_or("Equipment", |s| s.name())
+                                            }
                                             ItemKind::Food => "Food",
                                         };
                                         if slot.equipped {
@@ -296,7 +771,11 @@ impl Ui {
                                             display_equipped = "";
                                         }
                                         level = item.info.level.to_string();
-                                        cond = get_item_condition(item.item_durability);
+                                        cond = get_item_condition(
+                                            item.item_durability,
+                                            item.max_durability,
+                                        );
+                                        cond_fraction = item.durability_fraction();
                                     }
                                 }
 
@@ -321,9 +800,15 @@ impl Ui {
                                     ui.label(display_equipped);
                                 });
                                 row.col(|ui| {
+                                    let color = to_egui(&condition_color(cond));
+                                    ui.add(
+                                        egui::ProgressBar::new(cond_fraction)
+                                            .desired_width(text_height * 3.0)
+                                            .desired_height(text_height / 2.0)
+                                            .fill(color),
+                                    );
                                     ui.label(
-                                        egui::RichText::new(format!("{:?}", cond))
-                                            .color(to_egui(&condition_color(cond))),
+                                        egui::RichText::new(format!("{:?}", cond)).color(color),
                                     );
                                 });
 
@@ -331,6 +816,15 @@ impl Ui {
                             });
                         });
                 });
+                let pending_crafts = sim.world_info.pending_crafts;
+                if pending_crafts > 0 {
+                    let plural = if pending_crafts == 1 { "" } else { "s" };
+                    ui.colored_label(
+                        to_egui(&Color::Pink),
+                        format!("Crafting {pending_crafts} item{plural}..."),
+                    );
+                }
+                self.render_craft_preview(ui, sim);
                 ui.with_layout(egui::Layout::left_to_right(egui::Align::Min), |ui| {
                     if ui.button("Equip/Unequip/Eat (e)").clicked() {
                         self.ui_button = Some(UiButton::Equip);
@@ -352,6 +846,34 @@ impl Ui {
             });
     }
 
+    /// Shows what the two selected items will combine into, if that's already known.
+    fn render_craft_preview(&self, ui: &mut egui::Ui, sim: &crate::world::World) {
+        let mut selected = self.inventory_selected.iter().copied().collect::<Vec<_>>();
+        selected.sort();
+        let [a, b] = selected[..] else {
+            return;
+        };
+        match craft_preview(sim, a, b) {
+            Some(CraftPreview::CantCombine) => {
+                ui.colored_label(to_egui(&Color::Grey), "These can't be combined.");
+            }
+            Some(CraftPreview::Known(result)) => {
+                ui.horizontal(|ui| {
+                    ui.label("Combines into:");
+                    ui.colored_label(to_egui(&result.ty.get_color()), &result.name);
+                    ui.label(format!("({:?})", result.ty));
+                });
+            }
+            Some(CraftPreview::Unknown) => {
+                ui.colored_label(
+                    to_egui(&Color::Pink),
+                    "Combines into: unknown result (will be generated)",
+                );
+            }
+            None => {}
+        }
+    }
+
     pub fn render(&mut self, sim: &crate::world::World, memory: &crate::world::Memory) {
         egui_macroquad::ui(|egui_ctx| {
             if self.ui_selected {
@@ -360,6 +882,18 @@ impl Ui {
             if self.help_selected {
                 self.render_help(egui_ctx);
             }
+            if self.recipes_selected {
+                self.render_recipes(egui_ctx, sim);
+            }
+            if self.settings_selected {
+                self.render_settings(egui_ctx);
+            }
+            if self.description_selected {
+                self.render_description(egui_ctx, sim, memory);
+            }
+            if self.touch_controls {
+                self.render_touch_controls(egui_ctx);
+            }
             let bottom_bar_height = 32.0 * self.scale_factor();
             let player_pos = sim.get_player_pos();
             let grid_rect =
@@ -393,19 +927,15 @@ impl Ui {
                 location: (player_pos.x as usize, player_pos.y as usize),
                 layer: 2,
             }];
-            let fov = sim.get_fov();
             for pos in grid_rect {
                 let tile = &memory.tile_map[pos];
-                let bg = if fov.contains(&pos) { FOV_BG } else { OOS_BG };
+                let bg = if memory.fov.contains(&pos) {
+                    FOV_BG
+                } else {
+                    OOS_BG
+                };
                 if let Some(tile) = tile {
-                    let (character, color) = match tile.kind {
-                        TileKind::Floor => ('.', LIGHTGRAY),
-                        TileKind::Wall => ('#', WHITE),
-                        TileKind::YellowFloor => ('.', YELLOW),
-                        TileKind::YellowWall => ('#', YELLOW),
-                        TileKind::BloodyFloor => ('.', RED),
-                        TileKind::Stairs => ('>', LIGHTGRAY),
-                    };
+                    let (character, color) = tile_glyph(tile.kind);
                     glyphs.push(Glyph {
                         character,
                         color,
@@ -414,19 +944,7 @@ impl Ui {
                         layer: 0,
                     });
                     if let Some(ref item) = tile.item {
-                        let (character, color) = match item {
-                            Item::PendingCraft(..) => ('?', PINK),
-                            Item::Instance(ii) => {
-                                let char = match ii.info.kind {
-                                    ItemKind::MeleeWeapon => ')',
-                                    ItemKind::RangedWeapon => '/',
-                                    ItemKind::Armor => '[',
-                                    ItemKind::Food => '%',
-                                };
-                                let color = ii.info.ty.get_color().into();
-                                (char, color)
-                            }
-                        };
+                        let (character, color) = item_glyph(item);
                         glyphs.push(Glyph {
                             character,
                             color,
@@ -439,7 +957,7 @@ impl Ui {
                 if let Some(mob) = memory.mobs.get(&pos) {
                     let mob_kind_info = sim.get_mobkind_info(mob.kind);
                     glyphs.push(Glyph {
-                        character: mob_kind_info.char.chars().next().unwrap(),
+                        character: mob_glyph(&mob_kind_info.char, &mob_kind_info.name),
                         color: mob_kind_info.color.into(),
                         bg,
                         location: (pos.x as usize, pos.y as usize),
@@ -447,15 +965,18 @@ impl Ui {
                     });
                 }
             }
+            let sidebar_width = screen_width() * self.sidebar_fraction;
             self.render_glyphs(
                 &glyphs,
-                screen_width() * (1. / 4.),
+                &memory.light,
+                sidebar_width,
                 bottom_bar_height,
                 upper_left,
             );
+            self.render_hover_tooltip(egui_ctx, sim, memory);
 
             // Draw side panel UI.
-            self.render_side_ui(egui_ctx, sim, screen_width() * (1. / 4.));
+            self.render_side_ui(egui_ctx, sim, memory, sidebar_width);
             self.render_bottom_bar(egui_ctx, sim, bottom_bar_height);
         });
 
@@ -492,6 +1013,19 @@ impl Ui {
                                         .font(font.clone()),
                                 );
                                 ui.separator();
+                                ui.label(RichText::new("TURN:").color(white).font(font.clone()));
+                                ui.label(
+                                    RichText::new(format!("{}", sim.turns_taken()))
+                                        .font(font.clone()),
+                                );
+                                let elapsed =
+                                    (macroquad::time::get_time() - self.run_started_at) as u64;
+                                ui.label(
+                                    RichText::new(format!("{}:{:02}", elapsed / 60, elapsed % 60))
+                                        .weak()
+                                        .font(font.clone()),
+                                );
+                                ui.separator();
                                 ui.label(
                                     RichText::new("FONT SCALE:").color(white).font(font.clone()),
                                 );
@@ -502,15 +1036,83 @@ impl Ui {
                                 if response.drag_released() {
                                     self.user_scale_factor = self.tmp_scale_factor;
                                 }
+                                ui.separator();
+                                ui.label(RichText::new("SIDEBAR:").color(white).font(font.clone()));
+                                ui.add(
+                                    egui::Slider::new(
+                                        &mut self.sidebar_fraction,
+                                        SIDEBAR_FRACTION_RANGE,
+                                    )
+                                    .show_value(false),
+                                );
                                 if ui.button("Help (q)").clicked() {
                                     self.toggle_help();
                                 }
+                                if ui.button("Settings").clicked() {
+                                    self.toggle_settings();
+                                }
+                                ui.checkbox(&mut self.sound_enabled, "Sound");
+                                ui.checkbox(&mut self.touch_controls, "Touch controls");
+                                if let Some(seed) =
+                                    sim.current_level_seed().filter(|_| cfg!(debug_assertions))
+                                {
+                                    ui.label(RichText::new(format!("Floor seed: {seed}")).weak());
+                                    if ui.button("Regenerate floor").clicked() {
+                                        self.ui_button = Some(UiButton::RegenerateFloor);
+                                    }
+                                }
                             },
                         );
                     });
             });
     }
 
+    /// Shows what the player remembers about the tile under the mouse.
+    fn render_hover_tooltip(
+        &self,
+        egui_ctx: &egui::Context,
+        sim: &crate::world::World,
+        memory: &crate::world::Memory,
+    ) {
+        let Some(pos) = self.hovered_pos else {
+            return;
+        };
+        if egui_ctx.is_pointer_over_area() {
+            return;
+        }
+        let Some(tile) = &memory.tile_map[pos] else {
+            return;
+        };
+        egui::show_tooltip_at_pointer(egui_ctx, egui::Id::new("map_tooltip"), |ui| {
+            ui.label(tile_name(tile.kind));
+            match &tile.item {
+                Some(Item::Instance(ii)) => {
+                    ui.colored_label(to_egui(&ii.info.ty.get_color()), &ii.info.name);
+                    ui.label(RichText::new(&ii.info.description).italics());
+                }
+                Some(Item::PendingCraft(..)) => {
+                    ui.colored_label(to_egui(&Color::Pink), "Crafting in progress...");
+                }
+                None => {}
+            }
+            if let Some(mob) = memory.mobs.get(&pos) {
+                let mki = sim.get_mobkind_info(mob.kind);
+                let max_hp = mki.max_hp();
+                ui.colored_label(
+                    to_egui(&mki.color),
+                    format!(
+                        "{} (level {}, HP {}/{})",
+                        mki.name,
+                        mki.level,
+                        max_hp.saturating_sub(mob.damage),
+                        max_hp
+                    ),
+                );
+                ui.label(RichText::new(&mki.description).italics());
+            }
+        });
+    }
+
     fn scale_factor(&self) -> f32 {
         let game_scale = screen_width().min(screen_height());
         miniquad::window::dpi_scale() * game_scale / 1200.0 * self.user_scale_factor
@@ -527,6 +1129,7 @@ impl Ui {
         &self,
         egui_ctx: &egui::Context,
         sim: &crate::world::World,
+        memory: &crate::world::Memory,
         right_offset: f32,
     ) {
         let game_width = screen_width() - right_offset;
@@ -562,7 +1165,7 @@ impl Ui {
                         ui.set_width(ui.available_width());
                         ui.with_layout(egui::Layout::top_down(egui::Align::Min), |ui| {
                             egui::ScrollArea::vertical().show(ui, |ui| {
-                                for (i, mob) in sim.get_visible_mobs().iter().enumerate() {
+                                for mob in memory.visible_mobs(sim.get_player_pos()).iter() {
                                     let damage = mob.damage;
                                     let mob_kind = mob.kind;
                                     let mob_kind_def = sim.get_mobkind_info(mob_kind);
@@ -677,9 +1280,43 @@ impl Ui {
                                         },
                                     );
 
+                                    // Bare hands count as a normal-type melee weapon.
+                                    let melee_ty = sim
+                                        .inventory
+                                        .get_equipped_weapon_info(true)
+                                        .map_or(PokemonType::Normal, |w| w.ty);
+                                    let ranged_ty =
+                                        sim.inventory.get_equipped_weapon_info(false).map(|w| w.ty);
+                                    for (label, ty) in std::iter::once(("melee", melee_ty))
+                                        .chain(ranged_ty.map(|ty| ("ranged", ty)))
+                                    {
+                                        let eff = ty.get_effectiveness2(*type1, *type2);
+                                        let (eff_label, eff_color) = effectiveness_label(eff);
+                                        job.append(
+                                            &format!(" | vs {label}: "),
+                                            0.0,
+                                            egui::TextFormat {
+                                                font_id: self.get_details_font(),
+                                                color: Color32::WHITE,
+                                                ..Default::default()
+                                            },
+                                        );
+                                        job.append(
+                                            eff_label,
+                                            0.0,
+                                            egui::TextFormat {
+                                                font_id: self.get_details_font(),
+                                                color: to_egui(&eff_color),
+                                                ..Default::default()
+                                            },
+                                        );
+                                    }
+
                                     ui.label(job);
 
-                                    ui.push_id(i, |ui| {
+                                    // Key on the mob, not its place in the list, so an
+                                    // open header follows it when the order changes.
+                                    ui.push_id(mob.id, |ui| {
                                         egui::CollapsingHeader::new("Details...").show(ui, |ui| {
                                             ui.add(
                                                 egui::Label::new(
@@ -758,6 +1395,7 @@ impl Ui {
     fn render_glyphs(
         &mut self,
         glyphs: &[Glyph],
+        light: &HashMap<Pos, f32>,
         right_offset: f32,
         bottom_offset: f32,
         upper_left: Pos,
@@ -788,28 +1426,29 @@ impl Ui {
             })
             .collect::<Vec<_>>();
 
-        let width = screen_width() - right_offset;
-        let height = screen_height() - bottom_offset;
-        let game_size = width.min(height);
-        let offset_x = (screen_width() - game_size - right_offset) / 2. + 10.;
-        let offset_y = (screen_height() - game_size) / 2. + 10.;
+        let (offset_x, offset_y, game_size) = map_square(
+            (screen_width(), screen_height()),
+            right_offset,
+            bottom_offset,
+        );
         let sq_size = (screen_height() - offset_y * 2.) / self.grid_size as f32;
 
         let delta = self.camera_delta.unwrap_or((0.0, 0.0));
         let delta = (delta.0 * sq_size, delta.1 * sq_size);
 
+        let origin = (delta.0 + offset_x, delta.1 + offset_y);
         let translate_coords = |x, y, font_offset| {
             let off = if font_offset {
                 (0.25, 0.75)
             } else {
                 (0.5, 0.6)
             };
-            (
-                delta.0 + offset_x + sq_size * (x as f32 + off.0),
-                delta.1 + offset_y + sq_size * (y as f32 + off.1),
-            )
+            cell_to_screen((x, y), origin, sq_size, off)
         };
 
+        self.hovered_pos = screen_to_cell(mouse_position(), origin, sq_size, self.grid_size)
+            .map(|(x, y)| upper_left + Offset { x, y });
+
         // First, set the actual background of the grid to black
         draw_rectangle(offset_x, offset_y, game_size - 20., game_size - 20., BLACK);
 
@@ -831,12 +1470,11 @@ impl Ui {
                     && y >= offset_y
                     && y < game_size + offset_y - 20.0
                 {
-                    let off_from_center = (
-                        glyph.location.0 as i32 - (self.grid_size as i32 / 2),
-                        glyph.location.1 as i32 - (self.grid_size as i32 / 2),
+                    let world_pos = Pos::new(
+                        glyph.location.0 as i32 + upper_left.x,
+                        glyph.location.1 as i32 + upper_left.y,
                     );
-                    let dist_from_center_sq =
-                        ((off_from_center.0.pow(2) + off_from_center.1.pow(2)) as f32).powf(0.5);
+                    let brightness = light.get(&world_pos).copied().unwrap_or(0.0);
 
                     let flicker = flicker_dist.get([
                         get_time() / 2.0,
@@ -844,7 +1482,8 @@ impl Ui {
                     ]) as f32
                         * 0.4
                         + 1.;
-                    let attenuation = 1. / (dist_from_center_sq * flicker).max(2.0).min(4.0);
+                    // Unlit tiles are dim, lit ones up to twice as bright.
+                    let attenuation = 0.25 + 0.25 * (brightness * flicker).min(1.0);
                     let bg_hsl = macroquad::color::rgb_to_hsl(glyph.bg);
                     let bg_rgb =
                         macroquad::color::hsl_to_rgb(bg_hsl.0, bg_hsl.1, bg_hsl.2 * attenuation);
@@ -907,9 +1546,182 @@ impl Ui {
                         );
                     }
                 }
+                Animation::Aim(shot_animation) => {
+                    let interp = animation.time_elapsed / animation.duration;
+                    let mut color = macroquad::color::Color::from(shot_animation.color);
+                    color.a = 1.0 - interp;
+                    for cell in shot_animation.cells.iter().skip(1) {
+                        let cell = *cell - upper_left;
+                        if cell.x < 0
+                            || cell.x >= self.grid_size as i32
+                            || cell.y < 0
+                            || cell.y >= self.grid_size as i32
+                        {
+                            continue;
+                        }
+                        let (x, y) = translate_coords(cell.x, cell.y, false);
+                        draw_circle(x, y, 0.15 * sq_size, color);
+                    }
+                }
+                Animation::LevelTransition => {
+                    let interp = animation.time_elapsed / animation.duration;
+                    draw_rectangle(
+                        0.,
+                        0.,
+                        screen_width(),
+                        screen_height(),
+                        macroquad::color::Color::new(0., 0., 0., 1.0 - interp),
+                    );
+                }
             };
             animation.time_elapsed += get_frame_time();
         }
         self.animations.retain(|a| a.time_elapsed < a.duration);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn item_condition_scales_with_max_durability() {
+        let condition = get_item_condition;
+        assert!(matches!(condition(20, 20), ItemCondition::New));
+        assert!(matches!(condition(18, 20), ItemCondition::LikeNew));
+        assert!(matches!(condition(10, 20), ItemCondition::Good));
+        assert!(matches!(condition(1, 20), ItemCondition::Poor));
+        assert!(matches!(condition(0, 20), ItemCondition::Poor));
+        assert!(matches!(condition(5, 5), ItemCondition::New));
+        assert!(matches!(condition(0, 0), ItemCondition::Poor));
+    }
+
+    #[test]
+    fn effectiveness_labels() {
+        let label = |eff| effectiveness_label(eff).0;
+        assert_eq!(label(AttackEffectiveness::Zero), "Immune");
+        assert_eq!(label(AttackEffectiveness::Quarter), "Resist");
+        assert_eq!(label(AttackEffectiveness::Half), "Resist");
+        assert_eq!(label(AttackEffectiveness::One), "Neutral");
+        assert_eq!(label(AttackEffectiveness::Two), "Weak");
+        assert_eq!(label(AttackEffectiveness::Four), "Weak");
+    }
+
+    #[test]
+    fn touch_controls_map_to_player_actions() {
+        let action = |button: UiButton, fire| button.player_action(fire);
+        for dir in [NORTH, SOUTH, EAST, WEST] {
+            assert!(
+                matches!(action(UiButton::Move(dir), false), Some(PlayerAction::Move(d)) if d == dir)
+            );
+            assert!(
+                matches!(action(UiButton::Move(dir), true), Some(PlayerAction::Fire(d)) if d == dir)
+            );
+        }
+        assert!(matches!(
+            action(UiButton::Wait, false),
+            Some(PlayerAction::Wait)
+        ));
+        assert!(matches!(
+            action(UiButton::PickUp, true),
+            Some(PlayerAction::PickUp)
+        ));
+        assert!(action(UiButton::Inspect, false).is_none());
+    }
+
+    #[test]
+    fn screen_positions_map_back_to_their_cell() {
+        let (origin, sq_size) = ((40.0, -12.5), 24.0);
+        for cell in [(0, 0), (3, 7), (15, 15)] {
+            for off in [(0.0, 0.0), (0.5, 0.6), (0.25, 0.75), (0.99, 0.99)] {
+                let screen = cell_to_screen(cell, origin, sq_size, off);
+                assert_eq!(screen_to_cell(screen, origin, sq_size, 16), Some(cell));
+            }
+        }
+        let outside = [(39.0, 0.0), (40.0, -13.0), (40.0 + 16.0 * 24.0, 0.0)];
+        for screen in outside {
+            assert_eq!(screen_to_cell(screen, origin, sq_size, 16), None);
+        }
+    }
+
+    #[test]
+    fn monster_glyphs_fall_back_to_something_drawable() {
+        assert_eq!(mob_glyph("r", "rat"), 'r');
+        // Emoji, then a combining mark on its own, aren't in the font.
+        assert_eq!(mob_glyph("🐀", "rat"), 'r');
+        assert_eq!(mob_glyph("\u{301}", "Ogre"), 'O');
+        assert_eq!(mob_glyph("\u{301}g", "Ogre"), 'g');
+        assert_eq!(mob_glyph("", "  ghost"), 'g');
+        assert_eq!(mob_glyph("", "🐀"), '?');
+    }
+
+    #[test]
+    fn craft_previews_ignore_selection_order() {
+        use crate::world::tests::{give, info_named, test_world};
+        let mut world = test_world();
+        let (sword, bread) = (info_named(&world, "sword"), info_named(&world, "bread"));
+        let bow = info_named(&world, "bow");
+        world.world_info.recipes.insert((sword, bread), bow.clone());
+        let bread = give(&mut world, "bread");
+        let sword = give(&mut world, "sword");
+        let mail = give(&mut world, "mail");
+        let known = Some(CraftPreview::Known(bow));
+        assert_eq!(craft_preview(&world, bread, sword), known);
+        assert_eq!(craft_preview(&world, sword, bread), known);
+        assert_eq!(
+            craft_preview(&world, mail, sword),
+            Some(CraftPreview::Unknown)
+        );
+        assert_eq!(
+            craft_preview(&world, sword, mail),
+            Some(CraftPreview::Unknown)
+        );
+    }
+
+    #[test]
+    fn the_map_shrinks_as_the_sidebar_grows() {
+        let screen = (1000.0, 1000.0);
+        let mut last_size = f32::INFINITY;
+        for fraction in [
+            *SIDEBAR_FRACTION_RANGE.start(),
+            SIDEBAR_FRACTION,
+            *SIDEBAR_FRACTION_RANGE.end(),
+        ] {
+            let sidebar = screen.0 * fraction;
+            let (x, _, size) = map_square(screen, sidebar, 32.0);
+            assert!(size < last_size);
+            assert_eq!(size, screen.0 - sidebar);
+            // The drawn map ends where the sidebar starts.
+            assert!(x + size - 20.0 <= screen.0 - sidebar);
+            last_size = size;
+        }
+        // A short, wide screen is limited by height instead.
+        assert_eq!(map_square((2000.0, 600.0), 500.0, 32.0).2, 568.0);
+    }
+
+    #[test]
+    fn exported_map_shows_only_the_explored_room() {
+        use crate::world::tests::{item_named, spawn, test_world};
+        let mut sim = test_world();
+        for pos in Rect::new(0, 19, 0, 9) {
+            if !Rect::new(1, 5, 4, 6).contains(pos) {
+                sim[pos].kind = TileKind::Wall;
+            }
+        }
+        sim[Pos::new(4, 5)].item = Some(item_named(&sim, "sword"));
+        spawn(&mut sim, "rat", Pos::new(5, 4));
+        let mut memory = crate::world::Memory::new();
+        memory.update(&sim);
+        // Remembered tiles that don't connect to the player stay out.
+        memory.tile_map[Pos::new(100, 100)] = Some(sim.get_tile(Pos::new(1, 4)));
+        let text = export_map_text(&sim, &memory);
+        assert_eq!(
+            text,
+            "#######\n\
+             #....r#\n\
+             #.@.).#\n\
+             #.....#\n\
+             #######\n"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Recording a run's player actions to a file and playing them back, for reproducing bugs.
+//!
+//! A log is one JSON line holding the run seed, then one JSON line per action or change
+//! of rules. Replaying only reproduces a run if the game was given the same monster and
+//! item definitions.
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::world::{PlayerAction, Rules, World};
+
+/// Path to record player actions to.
+pub const RECORD_ENV: &str = "RECORD_INPUT";
+/// Path of a recording to play back at startup.
+pub const REPLAY_ENV: &str = "REPLAY_INPUT";
+
+/// Anything the player does that changes how the run plays out.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub enum InputEvent {
+    Action(PlayerAction),
+    /// The rules were changed from the settings window.
+    Rules(Rules),
+}
+
+impl InputEvent {
+    /// Applies the event to the world, returning whether it took a turn.
+    pub fn apply(&self, world: &mut World) -> bool {
+        match self {
+            InputEvent::Action(action) => world.do_player_action(action.clone()),
+            InputEvent::Rules(rules) => {
+                world.rules = *rules;
+                false
+            }
+        }
+    }
+}
+
+pub struct InputLog {
+    pub seed: u64,
+    pub events: Vec<InputEvent>,
+}
+
+impl InputLog {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next().ok_or("empty input log")?;
+        let seed =
+            serde_json::from_str(&header.map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+        let mut events = Vec::new();
+        for line in lines {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.is_empty() {
+                continue;
+            }
+            events.push(serde_json::from_str(&line).map_err(|e| e.to_string())?);
+        }
+        Ok(Self { seed, events })
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var(REPLAY_ENV).ok()?;
+        match Self::load(&path) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                macroquad::miniquad::error!("{}", format!("Could not load input log {path}: {e}"));
+                None
+            }
+        }
+    }
+
+    /// Feeds every event through the world, ticking after each one that took a turn.
+    /// `on_tick` runs after each such turn.
+    pub fn replay(&self, world: &mut World, mut on_tick: impl FnMut(&World)) {
+        for event in &self.events {
+            if event.apply(world) {
+                on_tick(world);
+            }
+        }
+    }
+}
+
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn create(path: &str, seed: u64) -> Result<Self, String> {
+        let mut file = File::create(path).map_err(|e| e.to_string())?;
+        writeln!(file, "{seed}").map_err(|e| e.to_string())?;
+        Ok(Self { file })
+    }
+
+    pub fn from_env(seed: u64) -> Option<Self> {
+        let path = std::env::var(RECORD_ENV).ok()?;
+        match Self::create(&path, seed) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                macroquad::miniquad::error!("{}", format!("Could not record to {path}: {e}"));
+                None
+            }
+        }
+    }
+
+    pub fn record(&mut self, event: &InputEvent) {
+        // Write each event as it happens so a crash still leaves a usable log.
+        let line = serde_json::to_string(event).unwrap();
+        if let Err(e) = writeln!(self.file, "{line}") {
+            macroquad::miniquad::warn!("{}", format!("Could not record input: {e}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{Offset, Pos};
+    use crate::world::tests::{give, spawn, test_world};
+
+    /// What a replay has to get right: where the player is, their health, and the mobs.
+    fn final_state(world: &World) -> (Pos, usize, Vec<String>) {
+        let mut mobs = world
+            .mobs
+            .iter()
+            .map(|(pos, mob)| format!("{pos:?} {mob:?}"))
+            .collect::<Vec<_>>();
+        mobs.sort();
+        (world.player_pos, world.player_damage, mobs)
+    }
+
+    fn session_world() -> World {
+        let mut world = test_world();
+        give(&mut world, "sword");
+        let pos = world.player_pos + Offset { x: 3, y: 0 };
+        spawn(&mut world, "rat", pos);
+        spawn(&mut world, "archer", Pos::new(15, 2));
+        world
+    }
+
+    #[test]
+    fn a_recorded_session_replays_to_the_same_state() {
+        let path = std::env::temp_dir().join(format!(
+            "everythingrl-replay-test-{}.jsonl",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let east = Offset { x: 1, y: 0 };
+        let mut rules = Rules::default();
+        rules.stab = true;
+        let session = [
+            InputEvent::Action(PlayerAction::Use(0)),
+            InputEvent::Rules(rules),
+            InputEvent::Action(PlayerAction::Move(east)),
+            InputEvent::Action(PlayerAction::Wait),
+            InputEvent::Action(PlayerAction::Move(east)),
+            InputEvent::Action(PlayerAction::Move(east)),
+            InputEvent::Action(PlayerAction::Move(east)),
+            InputEvent::Action(PlayerAction::Wait),
+        ];
+
+        let mut world = session_world();
+        let mut recorder = Recorder::create(path, 7).unwrap();
+        for event in &session {
+            recorder.record(event);
+            event.apply(&mut world);
+        }
+        drop(recorder);
+
+        let log = InputLog::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(log.seed, 7);
+        let mut replayed = session_world();
+        log.replay(&mut replayed, |_| {});
+        assert_eq!(replayed.rules, rules);
+        assert_eq!(final_state(&replayed), final_state(&world));
+    }
+}
@@ -0,0 +1,53 @@
+use enum_map::{Enum, EnumMap};
+use macroquad::audio::{load_sound, play_sound_once, Sound};
+
+#[derive(Enum, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SoundEffect {
+    Attack,
+    Hurt,
+    PickUp,
+    Descend,
+    Death,
+    Victory,
+}
+
+impl SoundEffect {
+    fn path(self) -> &'static str {
+        match self {
+            SoundEffect::Attack => "assets/sounds/attack.wav",
+            SoundEffect::Hurt => "assets/sounds/hurt.wav",
+            SoundEffect::PickUp => "assets/sounds/pickup.wav",
+            SoundEffect::Descend => "assets/sounds/descend.wav",
+            SoundEffect::Death => "assets/sounds/death.wav",
+            SoundEffect::Victory => "assets/sounds/victory.wav",
+        }
+    }
+}
+
+/// Sound effects loaded at startup. Any that fail to load are just silent.
+pub struct Sounds {
+    sounds: EnumMap<SoundEffect, Option<Sound>>,
+}
+
+impl Sounds {
+    pub async fn load() -> Sounds {
+        let mut sounds = EnumMap::default();
+        // The web build doesn't ship quad-snd's audio.js yet.
+        if cfg!(target_family = "wasm") {
+            return Sounds { sounds };
+        }
+        for (effect, sound) in sounds.iter_mut() {
+            match load_sound(effect.path()).await {
+                Ok(s) => *sound = Some(s),
+                Err(e) => macroquad::miniquad::error!("Failed to load {}: {}", effect.path(), e),
+            }
+        }
+        Sounds { sounds }
+    }
+
+    pub fn play(&self, effect: SoundEffect) {
+        if let Some(ref sound) = self.sounds[effect] {
+            play_sound_once(sound);
+        }
+    }
+}
@@ -1,11 +1,21 @@
+/// The first `max_chars` characters of `text`, or all of it if it's shorter.
 pub fn trim(text: &str, max_chars: usize) -> &str {
-    if text.chars().next().is_none() {
-        return "";
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_keeps_whole_characters() {
+        assert_eq!(trim("", 3), "");
+        assert_eq!(trim("héllo", 0), "");
+        assert_eq!(trim("héllo", 2), "hé");
+        assert_eq!(trim("héllo", 5), "héllo");
+        assert_eq!(trim("héllo", 100), "héllo");
     }
-    let length = text.len().min(max_chars);
-    let mut iter = text.char_indices();
-    let (end, _) = iter
-        .nth(length)
-        .unwrap_or(text.char_indices().last().unwrap());
-    &text[..end]
 }
@@ -1,22 +1,38 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
 
-use crate::grid::{self, Offset, Pos, TileMap, CARDINALS};
+use crate::grid::{self, Offset, Pos, TileMap};
 use crate::net::{
-    Area, AttackEffectiveness, Color, IdeaGuy, ItemDefinition, ItemKind, MonsterDefinition,
-    PokemonType,
+    Area, ArmorSlot, AttackEffectiveness, BossDefinition, Color, CraftStatus, IdeaGuy,
+    ItemDefinition, ItemKind, MonsterDefinition, PokemonType, StairsLock,
 };
-use crate::render::{Animation, AnimationState, ShotAnimation};
+use crate::render::{Animation, AnimationState, ShotAnimation, LEVEL_TRANSITION_DURATION};
+use crate::sound::SoundEffect;
 use enum_map::{enum_map, Enum, EnumMap};
+use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use rand::{seq::SliceRandom as _, Rng, SeedableRng};
 
 pub const FOV_RANGE: i32 = 8;
+/// How many tiles across the map view is, so Memory lights all of it.
+pub const VIEW_SIZE: i32 = 32;
 pub const STARTING_DURABILITY: usize = 20;
 pub const PLAYER_MAX_HEALTH: usize = 100;
 pub const RELOAD_DELAY: usize = 2;
 pub const SPEED_MUL: i32 = 8;
 pub const INVENTORY_LIMIT: usize = 10;
+/// The player's own dim glow, below any light item's radius so even a level 1
+/// torch lets them see further in the dark.
+pub const PLAYER_LIGHT_RADIUS: i32 = 2;
+/// How many levels stronger a mini-boss is than the monster it's based on.
+pub const MINI_BOSS_LEVEL_BONUS: usize = 2;
+/// How far away idle monsters hear the player fire or craft, through walls.
+pub const FIRE_NOISE_RADIUS: i32 = 10;
+pub const CRAFT_NOISE_RADIUS: i32 = 5;
+/// Damage the player takes for each turn spent on a hazard tile.
+pub const HAZARD_DAMAGE: usize = 5;
+/// Descents after which an unresolved craft falls apart back into its first reagent.
+pub const PENDING_CRAFT_MAX_LEVELS: usize = 2;
 
 pub const PICK_UP_MESSAGES: [&str; 5] = [
     "You see here a ",
@@ -35,6 +51,9 @@ pub enum TileKind {
     YellowWall,
     BloodyFloor,
     Stairs,
+    Torch,
+    /// Walkable, but hurts the player standing on it each turn.
+    Hazard,
 }
 
 impl TileKind {
@@ -45,12 +64,18 @@ impl TileKind {
     pub fn is_walkable(self) -> bool {
         TILE_INFOS[self].walkable
     }
+
+    /// Radius of the light this tile gives off, if any.
+    pub fn light_radius(self) -> i32 {
+        TILE_INFOS[self].light
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct ItemInstance {
     pub info: Rc<ItemInfo>,
     pub item_durability: usize,
+    pub max_durability: usize,
 }
 
 impl ItemInstance {
@@ -58,8 +83,14 @@ impl ItemInstance {
         ItemInstance {
             info,
             item_durability,
+            max_durability: item_durability,
         }
     }
+
+    /// Remaining durability as a fraction of this item's own maximum.
+    pub fn durability_fraction(&self) -> f32 {
+        self.item_durability as f32 / self.max_durability.max(1) as f32
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -71,17 +102,25 @@ pub enum Item {
 pub struct TileKindInfo {
     pub opaque: bool,
     pub walkable: bool,
+    pub light: i32,
 }
 
 lazy_static! {
     pub static ref TILE_INFOS: EnumMap<TileKind, TileKindInfo> = enum_map! {
-        TileKind::Floor | TileKind::YellowFloor | TileKind::BloodyFloor | TileKind::Stairs=> TileKindInfo {
+        TileKind::Floor | TileKind::YellowFloor | TileKind::BloodyFloor | TileKind::Stairs | TileKind::Hazard => TileKindInfo {
             opaque: false,
             walkable: true,
+            light: 0,
         },
         TileKind::Wall | TileKind::YellowWall => TileKindInfo {
             opaque: true,
             walkable: false,
+            light: 0,
+        },
+        TileKind::Torch => TileKindInfo {
+            opaque: true,
+            walkable: false,
+            light: 6,
         },
     };
 }
@@ -104,9 +143,16 @@ pub enum MobAi {
 
 #[derive(Hash, Debug, Clone)]
 pub struct Mob {
+    /// Unique per world, assigned by World::add_mob. Stays put while the mob moves.
+    pub id: usize,
     pub kind: MobKind,
     pub damage: usize,
     pub reload: usize,
+    /// Step on which this ranged mob took aim. It fires on a later step.
+    pub aiming_since: Option<usize>,
+    /// Extra attack and defense levels from looted equipment.
+    pub attack_bonus: usize,
+    pub defense_bonus: usize,
     pub actions: i32,
     pub ai: MobAi,
 }
@@ -140,11 +186,16 @@ impl From<u8> for Speed {
 }
 
 impl Mob {
-    pub fn new(kind: MobKind) -> Self {
+    pub fn new(kind: MobKind, info: &MobKindInfo) -> Self {
         Self {
+            id: 0,
             kind,
             damage: 0,
-            reload: RELOAD_DELAY,
+            // Only ranged mobs need to reload; melee mobs attack whenever adjacent.
+            reload: if info.ranged { RELOAD_DELAY } else { 0 },
+            aiming_since: None,
+            attack_bonus: 0,
+            defense_bonus: 0,
             actions: 0,
             ai: MobAi::Idle,
         }
@@ -159,6 +210,8 @@ pub struct ItemInfo {
     pub ty2: Option<PokemonType>,
     pub description: String,
     pub kind: ItemKind,
+    pub unique: bool,
+    pub slot: Option<ArmorSlot>,
 }
 
 impl ItemInfo {
@@ -168,6 +221,14 @@ impl ItemInfo {
             _ => 0,
         }
     }
+    /// How far this item lights up the area around the player when equipped.
+    pub fn get_light_radius(&self) -> i32 {
+        match (self.kind, self.ty) {
+            (ItemKind::Food, _) => 0,
+            (_, PokemonType::Fire | PokemonType::Electric) => 3 + self.level as i32,
+            _ => 0,
+        }
+    }
     /// If ingested, how much does this heal?
     pub fn get_heal_amount(&self, armor_types: &[PokemonType]) -> i32 {
         use AttackEffectiveness::*;
@@ -210,6 +271,8 @@ pub struct MobKindInfo {
     pub death: String,
     pub ranged: bool,
     pub speed: Speed,
+    pub blocks_sight: bool,
+    pub loots: bool,
 }
 
 impl MobKindInfo {
@@ -224,24 +287,87 @@ pub struct BossInfo {
     pub periodic_messages: Vec<String>,
 }
 
-fn calc_damage(
-    att_level: usize,
-    def_level: usize,
-    eff: AttackEffectiveness,
-    _attacker_is_player: bool,
-    is_ranged: bool,
-) -> usize {
-    // Base 4 mult.
-    let mult = eff.get_scale();
-    let mut damage = (att_level + 1) * mult;
-    if damage > 0 {
-        damage /= def_level + 1;
-    };
-    if is_ranged {
-        damage /= 2;
+/// Rules the player can change mid-run from the settings window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub struct Rules {
+    /// Easy mode: the recipe book lists every generated recipe, not just the ones crafted so far.
+    pub recipe_book_shows_all: bool,
+    /// Whether looting monsters pick up the items they walk over.
+    pub mobs_loot_items: bool,
+    /// Whether items one level apart can be crafted together, not just equal levels.
+    pub craft_adjacent_levels: bool,
+    /// Whether same-type attacks deal bonus damage. Off by default: nearly every
+    /// monster attacks with its own type, while the player rarely can.
+    pub stab: bool,
+    /// Whether the player can keep walking around the dungeon after winning.
+    /// Combat is over either way.
+    pub free_roam_after_victory: bool,
+}
+
+impl Rules {
+    /// How far apart in level two items can be and still craft together.
+    pub fn max_craft_level_diff(&self) -> usize {
+        if self.craft_adjacent_levels {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// Knobs for the damage formula. The defaults are the standard balance.
+#[derive(Debug, Clone)]
+pub struct CombatConfig {
+    /// Added to the attacker's level to get its base damage.
+    pub base_damage: usize,
+    /// Damage per point of base damage on a neutral hit. Effectiveness scales this.
+    pub damage_multiplier: usize,
+    /// Same-type attack damage multiplier, as (numerator, denominator), when the
+    /// stab rule is on.
+    pub stab_bonus: (usize, usize),
+    /// Ranged attacks deal their damage divided by this.
+    pub ranged_divisor: usize,
+    /// Every hit deals at least this much.
+    pub min_damage: usize,
+}
+
+impl Default for CombatConfig {
+    fn default() -> Self {
+        CombatConfig {
+            base_damage: 1,
+            damage_multiplier: 4,
+            stab_bonus: (5, 4),
+            ranged_divisor: 2,
+            min_damage: 1,
+        }
+    }
+}
+
+impl CombatConfig {
+    fn calc_damage(
+        &self,
+        att_level: usize,
+        def_level: usize,
+        eff: AttackEffectiveness,
+        stab: bool,
+        _attacker_is_player: bool,
+        is_ranged: bool,
+    ) -> usize {
+        // get_scale is relative to a neutral hit's.
+        let mut damage = (att_level + self.base_damage) * self.damage_multiplier * eff.get_scale()
+            / AttackEffectiveness::One.get_scale();
+        if damage > 0 {
+            damage /= def_level + 1;
+        };
+        if stab {
+            damage = damage * self.stab_bonus.0 / self.stab_bonus.1.max(1);
+        }
+        if is_ranged {
+            damage /= self.ranged_divisor.max(1);
+        }
+        damage = damage.max(self.min_damage);
+        damage
     }
-    damage = damage.max(1);
-    damage
 }
 
 /// Contains post-processed content definitions parsed from AI-generated data.
@@ -250,11 +376,16 @@ pub struct WorldInfo {
     pub areas: Vec<Area>,
     pub item_kinds: Vec<Rc<ItemInfo>>,
     pub monster_kinds: Vec<MobKindInfo>,
+    /// The final boss. Killing it wins the game.
     pub boss_info: Option<BossInfo>,
+    /// The optional mini-boss of each area.
+    pub mini_bosses: Vec<Option<BossInfo>>,
     pub monsters_per_level: Vec<Vec<MobKind>>,
     pub equipment_per_level: Vec<Vec<Rc<ItemInfo>>>,
     pub recipes: HashMap<(Rc<ItemInfo>, Rc<ItemInfo>), Rc<ItemInfo>>,
     pub pending_recipes: HashSet<(Rc<ItemInfo>, Rc<ItemInfo>)>,
+    /// Number of crafts waiting on the server.
+    pub pending_crafts: usize,
     pub level_blurbs: Vec<String>,
 }
 
@@ -267,17 +398,28 @@ impl WorldInfo {
             monsters_per_level: Vec::new(),
             equipment_per_level: Vec::new(),
             pending_recipes: HashSet::new(),
+            pending_crafts: 0,
             recipes: HashMap::new(),
             level_blurbs: Vec::new(),
             boss_info: None,
+            mini_bosses: Vec::new(),
         }
     }
 
-    pub fn update(&mut self, ig: &mut IdeaGuy) {
-        for i in self.areas.len()..ig.areas.as_ref().unwrap().len() {
-            self.areas.push(ig.areas.as_ref().unwrap()[i].clone());
+    /// Adds any areas, items, monsters and boss we haven't seen yet. This
+    /// doesn't need the server, so a world can also be built from
+    /// hand-written definitions.
+    pub fn add_definitions(
+        &mut self,
+        areas: &[Area],
+        items: &[ItemDefinition],
+        monsters: &[MonsterDefinition],
+        boss: &BossDefinition,
+    ) {
+        for i in self.areas.len()..areas.len() {
+            self.areas.push(areas[i].clone());
         }
-        for item in ig.items.iter().flatten() {
+        for item in items {
             if self.item_kinds.iter().any(|e| e.name == item.name) {
                 continue;
             }
@@ -287,6 +429,8 @@ impl WorldInfo {
                 ty,
                 kind,
                 description,
+                unique,
+                slot,
                 ..
             } = item.clone();
             self.item_kinds.push(Rc::new(ItemInfo {
@@ -296,31 +440,12 @@ impl WorldInfo {
                 ty2: None,
                 description,
                 kind,
+                unique,
+                slot,
             }));
         }
-        let boss = &ig.boss.as_ref().unwrap();
-        if self.boss_info.is_none() {
-            self.monster_kinds.push(MobKindInfo {
-                name: boss.name.clone(),
-                char: boss.char.clone(),
-                color: boss.color,
-                attack_type: boss.attack_type,
-                type1: boss.type1,
-                type2: boss.type2,
-                description: boss.description.clone(),
-                level: 8,
-                seen: boss.intro_message.clone(),
-                attack: boss.attack_messages.clone(),
-                death: boss.game_victory_paragraph.clone(),
-                ranged: true,
-                speed: Speed::Slow,
-            });
-            self.boss_info = Some(BossInfo {
-                mob_kind: MobKind(self.monster_kinds.len() - 1),
-                periodic_messages: boss.periodic_messages.clone(),
-            })
-        }
-        for mob in ig.monsters.iter().flatten().chain(&[]) {
+        self.add_boss(boss);
+        for mob in monsters {
             if self.monster_kinds.iter().any(|m| m.name == mob.name) {
                 continue;
             }
@@ -338,6 +463,8 @@ impl WorldInfo {
                 death,
                 ranged,
                 speed,
+                blocks_sight,
+                loots,
             } = mob.clone();
             let speed = speed.into();
             self.monster_kinds.push(MobKindInfo {
@@ -354,6 +481,31 @@ impl WorldInfo {
                 death,
                 ranged,
                 speed,
+                blocks_sight,
+                loots,
+            });
+        }
+
+        self.mini_bosses.resize(areas.len(), None);
+        for (i, area) in areas.iter().enumerate() {
+            if self.mini_bosses[i].is_some() {
+                continue;
+            }
+            let Some(base) = area
+                .mini_boss
+                .as_ref()
+                .and_then(|name| self.monster_kinds.iter().find(|k| &k.name == name))
+            else {
+                continue;
+            };
+            let mut mki = base.clone();
+            // A name of its own, so it can't be mistaken for the ordinary kind.
+            mki.name = format!("Elite {}", base.name);
+            mki.level += MINI_BOSS_LEVEL_BONUS;
+            self.monster_kinds.push(mki);
+            self.mini_bosses[i] = Some(BossInfo {
+                mob_kind: MobKind(self.monster_kinds.len() - 1),
+                periodic_messages: Vec::new(),
             });
         }
 
@@ -364,10 +516,8 @@ impl WorldInfo {
                 .map(MobKind)
         };
 
-        self.monsters_per_level = ig
-            .areas
+        self.monsters_per_level = areas
             .iter()
-            .flatten()
             .map(|area| {
                 area.enemies
                     .iter()
@@ -379,10 +529,8 @@ impl WorldInfo {
         let get_equipment_by_name =
             |name: &String| self.item_kinds.iter().find(|k| &k.name == name);
 
-        self.equipment_per_level = ig
-            .areas
+        self.equipment_per_level = areas
             .iter()
-            .flatten()
             .map(|area| {
                 area.equipment
                     .iter()
@@ -391,16 +539,61 @@ impl WorldInfo {
                     .chain(area.food.iter())
                     // NOTE: we may be missing some
                     .filter_map(get_equipment_by_name)
+                    .filter(|ii| !ii.unique)
                     .cloned()
                     .collect()
             })
             .collect();
-        self.level_blurbs = ig
-            .areas
+        self.level_blurbs = areas
             .iter()
-            .flatten()
             .map(|area| format!("{}: {}", area.name, area.blurb.clone()))
             .collect();
+    }
+
+    /// Adds the final boss, unless there already is one.
+    fn add_boss(&mut self, boss: &BossDefinition) {
+        if self.boss_info.is_some() {
+            return;
+        }
+        self.monster_kinds.push(MobKindInfo {
+            name: boss.name.clone(),
+            char: boss.char.clone(),
+            color: boss.color,
+            attack_type: boss.attack_type,
+            type1: boss.type1,
+            type2: boss.type2,
+            description: boss.description.clone(),
+            level: 8,
+            seen: boss.intro_message.clone(),
+            attack: boss.attack_messages.clone(),
+            death: boss.game_victory_paragraph.clone(),
+            ranged: true,
+            speed: Speed::Slow,
+            blocks_sight: false,
+            loots: false,
+        });
+        self.boss_info = Some(BossInfo {
+            mob_kind: MobKind(self.monster_kinds.len() - 1),
+            periodic_messages: boss.periodic_messages.clone(),
+        })
+    }
+
+    /// Falls back to the pre-generated boss if none was ever defined, so
+    /// every world can be won.
+    pub fn ensure_boss(&mut self) {
+        if self.boss_info.is_none() {
+            macroquad::miniquad::warn!("No boss defined, using the fallback boss");
+            self.add_boss(&crate::net::fallback_boss());
+        }
+    }
+
+    pub fn update(&mut self, ig: &mut IdeaGuy) {
+        self.add_definitions(
+            ig.areas.as_ref().unwrap(),
+            ig.items.as_deref().unwrap_or_default(),
+            ig.monsters.as_deref().unwrap_or_default(),
+            ig.boss.as_ref().unwrap(),
+        );
 
         for (&(a, b), &c) in ig.recipes.iter() {
             let ek_by_name = |name: &str| {
@@ -431,31 +624,98 @@ impl WorldInfo {
             let ig_b = ig_equip_by_name(&b.name);
             ig.craft(ig_a, ig_b);
         }
+        self.pending_crafts = ig.num_pending_crafts() + self.pending_recipes.len();
     }
 
     pub fn get_mobkind_info(&self, kind: MobKind) -> &MobKindInfo {
         &self.monster_kinds[kind.0]
     }
 
+    /// Whether this is the final boss, whose death wins the game.
+    pub fn is_final_boss(&self, kind: MobKind) -> bool {
+        self.boss_info.as_ref().is_some_and(|b| b.mob_kind == kind)
+    }
+
+    /// The monster that has to die to unlock a level's stairs. A lock naming the
+    /// level's mini-boss means the mini-boss itself, not any monster of its kind.
+    pub fn get_defeat_lock_kind(&self, level: usize, name: &str) -> Option<MobKind> {
+        let mini_boss = self
+            .areas
+            .get(level)
+            .filter(|area| area.mini_boss.as_deref() == Some(name))
+            .and_then(|_| self.mini_bosses.get(level)?.as_ref())
+            .map(|b| b.mob_kind);
+        mini_boss.or_else(|| {
+            self.monster_kinds
+                .iter()
+                .position(|k| k.name == name)
+                .map(MobKind)
+        })
+    }
+
+    /// Info for the final boss or any mini-boss of this kind.
+    pub fn get_boss_info(&self, kind: MobKind) -> Option<&BossInfo> {
+        self.boss_info
+            .iter()
+            .chain(self.mini_bosses.iter().flatten())
+            .find(|b| b.mob_kind == kind)
+    }
+
+    fn get_craft_status(
+        &self,
+        ig: &IdeaGuy,
+        ii1: &Rc<ItemInfo>,
+        ii2: &Rc<ItemInfo>,
+    ) -> CraftStatus {
+        if self.pending_recipes.contains(&(ii1.clone(), ii2.clone()))
+            || self.pending_recipes.contains(&(ii2.clone(), ii1.clone()))
+        {
+            return CraftStatus::Queued;
+        }
+        let ig_index = |ii: &ItemInfo| ig.items.iter().flatten().position(|x| x.name == ii.name);
+        match (ig_index(ii1), ig_index(ii2)) {
+            (Some(i), Some(j)) => ig
+                .get_craft_status(i, j)
+                .or_else(|| ig.get_craft_status(j, i))
+                .unwrap_or(CraftStatus::Generating),
+            _ => CraftStatus::Generating,
+        }
+    }
+
+    /// The already generated result of crafting these reagents, in either order.
+    pub fn get_recipe_result(
+        &self,
+        ii1: &Rc<ItemInfo>,
+        ii2: &Rc<ItemInfo>,
+    ) -> Option<Rc<ItemInfo>> {
+        self.recipes
+            .get(&(ii1.clone(), ii2.clone()))
+            .or_else(|| self.recipes.get(&(ii2.clone(), ii1.clone())))
+            .cloned()
+    }
+
     fn craft_inner(&mut self, ii1: Rc<ItemInfo>, ii2: Rc<ItemInfo>) -> Item {
-        if let Some(ek3) = self.recipes.get(&(ii1.clone(), ii2.clone())) {
-            Item::Instance(ItemInstance {
-                info: ek3.clone(),
-                item_durability: STARTING_DURABILITY,
-            })
+        if let Some(ek3) = self.get_recipe_result(&ii1, &ii2) {
+            Item::Instance(ItemInstance::new(ek3, STARTING_DURABILITY))
         } else {
-            self.pending_recipes.insert((ii1.clone(), ii2.clone()));
+            // Don't ask for the same pair twice just because it's in the other order.
+            if !self.pending_recipes.contains(&(ii2.clone(), ii1.clone())) {
+                self.pending_recipes.insert((ii1.clone(), ii2.clone()));
+            }
             Item::PendingCraft(ii1, ii2)
         }
     }
 
-    fn craft(&mut self, item1: Item, item2: Item) -> Result<Item, CraftError> {
+    fn craft(&mut self, item1: Item, item2: Item, max_diff: usize) -> Result<Item, CraftError> {
         match (item1, item2) {
             (Item::Instance(ei1), Item::Instance(ei2)) => {
-                if ei1.info.level == ei2.info.level {
-                    Ok(self.craft_inner(ei1.info, ei2.info))
-                } else {
+                if ei1.info.level.abs_diff(ei2.info.level) > max_diff {
                     Err(CraftError::BadLevel)
+                } else if ei2.info.level > ei1.info.level {
+                    // The result is based on the first item, so lead with the higher level.
+                    Ok(self.craft_inner(ei2.info, ei1.info))
+                } else {
+                    Ok(self.craft_inner(ei1.info, ei2.info))
                 }
             }
             _ => Err(CraftError::ReagentPending),
@@ -472,17 +732,53 @@ enum CraftError {
 pub struct InventoryItem {
     pub item: Item,
     pub equipped: bool,
+    /// How many times the player has descended while this was a pending craft.
+    pub levels_pending: usize,
+}
+
+/// How the inventory is rearranged after each action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InventoryOrder {
+    /// Equipped gear first, then everything else grouped by kind.
+    #[default]
+    Sorted,
+    /// Equipped gear first; everything else stays where it was.
+    EquippedFirst,
+    /// Nothing moves. New items go at the end.
+    Manual,
+}
+
+impl InventoryOrder {
+    pub fn next(self) -> Self {
+        match self {
+            InventoryOrder::Sorted => InventoryOrder::EquippedFirst,
+            InventoryOrder::EquippedFirst => InventoryOrder::Manual,
+            InventoryOrder::Manual => InventoryOrder::Sorted,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            InventoryOrder::Sorted => "sorted by kind",
+            InventoryOrder::EquippedFirst => "equipped items first",
+            InventoryOrder::Manual => "in pickup order",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Inventory {
     pub items: Vec<InventoryItem>,
+    pub order: InventoryOrder,
 }
 
 impl Inventory {
     // All of these methods suck, refactor.
     fn new() -> Self {
-        Self { items: vec![] }
+        Self {
+            items: vec![],
+            order: InventoryOrder::default(),
+        }
     }
 
     fn damage_weapon(&mut self, melee: bool) -> Option<Rc<ItemInfo>> {
@@ -519,6 +815,18 @@ impl Inventory {
         deleted
     }
 
+    /// Equipped items that the next use will destroy.
+    pub fn get_about_to_break(&self) -> Vec<Rc<ItemInfo>> {
+        self.items
+            .iter()
+            .filter(|x| x.equipped)
+            .filter_map(|x| match x.item {
+                Item::Instance(ref i) if i.item_durability == 1 => Some(i.info.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
     fn get_equipped_weapon(&mut self, melee: bool) -> Option<&mut ItemInstance> {
         self.items
             .iter_mut()
@@ -534,7 +842,7 @@ impl Inventory {
             })
     }
 
-    fn get_equipped_weapon_info(&self, melee: bool) -> Option<Rc<ItemInfo>> {
+    pub fn get_equipped_weapon_info(&self, melee: bool) -> Option<Rc<ItemInfo>> {
         self.items
             .iter()
             .filter(|x| x.equipped)
@@ -607,11 +915,34 @@ impl Inventory {
             .collect()
     }
 
+    /// Rearranges the items according to `order`. The sorts are stable, so items
+    /// that compare equal keep their slots.
+    fn arrange(&mut self) {
+        match self.order {
+            InventoryOrder::Sorted => self.sort(),
+            InventoryOrder::EquippedFirst => self.items.sort_by_key(|x| match x {
+                InventoryItem {
+                    item: Item::Instance(ek),
+                    equipped: true,
+                    ..
+                } => match ek.info.kind {
+                    ItemKind::MeleeWeapon => 1,
+                    ItemKind::RangedWeapon => 2,
+                    ItemKind::Armor => 3,
+                    ItemKind::Food => 4,
+                },
+                _ => 4,
+            }),
+            InventoryOrder::Manual => {}
+        }
+    }
+
     fn sort(&mut self) {
         self.items.sort_by_key(|x| match x {
             InventoryItem {
                 item: Item::Instance(ek),
                 equipped,
+                ..
             } => match (equipped, ek.info.kind) {
                 (true, ItemKind::MeleeWeapon) => 1,
                 (true, ItemKind::RangedWeapon) => 2,
@@ -631,6 +962,7 @@ impl Inventory {
         self.items.push(InventoryItem {
             item,
             equipped: false,
+            levels_pending: 0,
         });
         if self.items.len() > INVENTORY_LIMIT {
             for i in 0..self.items.len() {
@@ -668,11 +1000,13 @@ impl Inventory {
             self.items[i].equipped = false;
             true
         } else if let Item::Instance(ref ii) = self.items[i].item {
+            let armor_slot = ii.info.slot;
             // Unequip another item if that slot is full.
             let max_per_slot = |slot: ItemKind| match slot {
                 ItemKind::MeleeWeapon => 1,
                 ItemKind::RangedWeapon => 1,
-                ItemKind::Armor => 2,
+                // One piece per body part.
+                ItemKind::Armor => ArmorSlot::ALL.len(),
                 ItemKind::Food => 0,
             };
             let max = max_per_slot(ii.info.kind);
@@ -692,10 +1026,21 @@ impl Inventory {
                     }
                 })
                 .filter(|(_i, other_ii)| other_ii.info.kind == ii.info.kind)
-                .map(|(i, _)| i)
+                .map(|(i, ii)| (i, ii.info.slot))
                 .collect::<Vec<_>>();
-            if other_equipped_in_slot.len() >= max {
-                self.items[other_equipped_in_slot[0]].equipped = false;
+            // Armor for the same body part gets swapped out first.
+            let same_armor_slot = other_equipped_in_slot
+                .iter()
+                .find(|(_, slot)| armor_slot.is_some() && *slot == armor_slot);
+            if let Some(&(j, _)) = same_armor_slot {
+                self.items[j].equipped = false;
+            } else if other_equipped_in_slot.len() >= max {
+                // Slotless armor fills whatever's free, so it's the first to go.
+                let (j, _) = other_equipped_in_slot
+                    .iter()
+                    .find(|(_, slot)| slot.is_none())
+                    .unwrap_or(&other_equipped_in_slot[0]);
+                self.items[*j].equipped = false;
             }
 
             self.items[i].equipped = true;
@@ -717,20 +1062,39 @@ pub struct World {
     pub inventory: Inventory,
     pub log: VecDeque<(Vec<(String, Color)>, usize)>,
     pub untriggered_animations: Vec<AnimationState>,
+    pub untriggered_sounds: Vec<SoundEffect>,
     pub victory: bool,
+    pub combat: CombatConfig,
+    pub rules: Rules,
+    /// Kinds of monsters the player has killed.
+    pub defeated: HashSet<MobKind>,
+    /// Recipes the player has successfully crafted, in order of discovery.
+    pub discovered_recipes: IndexMap<(Rc<ItemInfo>, Rc<ItemInfo>), Rc<ItemInfo>>,
+    /// Progress of each pending craft in the inventory, as of the last update_defs.
+    craft_statuses: HashMap<(Rc<ItemInfo>, Rc<ItemInfo>), CraftStatus>,
     stairs: HashMap<Pos, Pos>,
     level_id: usize,
-    rng: rand::rngs::SmallRng,
+    /// Noises made this turn, as (position, radius). Idle mobs in earshot come looking.
+    noises: Vec<(Pos, i32)>,
+    /// The seed each level was generated from.
+    pub level_seeds: Vec<u64>,
+    /// Picks flavor text. ChaCha8 has a fixed algorithm, unlike SmallRng, which
+    /// differs between 32- and 64-bit targets, or StdRng, which may change between
+    /// rand versions, so a seed reads the same everywhere.
+    rng: rand_chacha::ChaCha8Rng,
     step: usize,
+    next_mob_id: usize,
 }
 
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub enum PlayerAction {
     Move(Offset),
     Fire(Offset),
     PickUp,
     Use(usize),
     Drop(usize),
-    Craft(usize, usize),
+    /// Crafts the first two items, then the result with each following item.
+    Craft(Vec<usize>),
     Wait,
 }
 
@@ -745,22 +1109,34 @@ impl World {
             }),
             world_info: WorldInfo::new(),
             mobs: HashMap::new(),
-            rng: rand::rngs::SmallRng::seed_from_u64(72),
+            rng: rand_chacha::ChaCha8Rng::seed_from_u64(72),
             inventory: Inventory::new(),
             victory: false,
+            combat: CombatConfig::default(),
+            rules: Rules::default(),
+            defeated: HashSet::new(),
+            discovered_recipes: IndexMap::new(),
+            craft_statuses: HashMap::new(),
             log: VecDeque::new(),
             untriggered_animations: Vec::new(),
+            untriggered_sounds: Vec::new(),
             stairs: HashMap::new(),
             level_id: 0,
+            noises: Vec::new(),
+            level_seeds: Vec::new(),
             step: 1,
+            next_mob_id: 0,
         }
     }
 
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    }
+
     pub fn post_init(&mut self) {
-        self.log_message(vec![(
-            self.world_info.level_blurbs[0].clone(),
-            Color::White,
-        )]);
+        if let Some(blurb) = self.world_info.level_blurbs.first() {
+            self.log_message(vec![(blurb.clone(), Color::White)]);
+        }
     }
 
     pub fn add_stairs(&mut self, pos: Pos, dest: Pos) {
@@ -782,20 +1158,111 @@ impl World {
 
     pub fn update_defs(&mut self, ig: &mut IdeaGuy) {
         self.world_info.update(ig);
-        let mut msgs = vec![];
+        let mut crafted = vec![];
         for item in &mut self.inventory.items {
             if let Item::PendingCraft(a, b) = item.item.clone() {
-                if let Some(c) = self.world_info.recipes.get(&(a.clone(), b.clone())) {
+                if let Some(c) = self.world_info.get_recipe_result(&a, &b) {
                     item.item = Item::Instance(ItemInstance::new(c.clone(), STARTING_DURABILITY));
-                    msgs.push(Self::get_craft_msg(a.clone(), b.clone(), c.clone()));
+                    crafted.push((a, b, c));
                 }
             }
         }
-        for msg in msgs {
-            self.log_message(msg);
+        for (a, b, c) in crafted {
+            self.log_message(Self::get_craft_msg(a.clone(), b.clone(), c.clone()));
+            self.discover_recipe(a, b, c);
+        }
+        self.craft_statuses.clear();
+        for item in &self.inventory.items {
+            if let Item::PendingCraft(a, b) = &item.item {
+                let status = self.world_info.get_craft_status(ig, a, b);
+                self.craft_statuses.insert((a.clone(), b.clone()), status);
+            }
+        }
+    }
+
+    /// Turns crafts the server never answered back into their first reagent,
+    /// so they don't hold an inventory slot forever.
+    fn reclaim_stale_crafts(&mut self) {
+        let mut reclaimed = vec![];
+        for item in &mut self.inventory.items {
+            if let Item::PendingCraft(a, _) = &item.item {
+                item.levels_pending += 1;
+                if item.levels_pending >= PENDING_CRAFT_MAX_LEVELS {
+                    let a = a.clone();
+                    item.item = Item::Instance(ItemInstance::new(a.clone(), STARTING_DURABILITY));
+                    item.levels_pending = 0;
+                    reclaimed.push(a);
+                }
+            }
+        }
+        for a in reclaimed {
+            self.log_message(vec![
+                (
+                    "Your unfinished craft falls apart, leaving a ".into(),
+                    Color::White,
+                ),
+                (a.name.clone(), a.ty.get_color()),
+                (".".into(), Color::White),
+            ]);
+        }
+    }
+
+    /// Puts the player at the start of a later level, equipped with the given gear.
+    /// For testing late-game content.
+    pub fn start_at_level(&mut self, level: usize, start: Pos, gear: Vec<Rc<ItemInfo>>) {
+        self.level_id = level;
+        self.player_pos = start;
+        for info in gear {
+            self.inventory
+                .add(Item::Instance(ItemInstance::new(info, STARTING_DURABILITY)));
+            self.inventory.toggle_equip(self.inventory.items.len() - 1);
+        }
+    }
+
+    /// Warns, right after it was worn down, that equipped gear of this kind
+    /// will break the next time it's used.
+    fn warn_about_to_break(&mut self, kind: ItemKind) {
+        for info in self.inventory.get_about_to_break() {
+            if info.kind == kind {
+                self.log_message(vec![
+                    ("Your ".into(), Color::White),
+                    (info.name.clone(), info.ty.get_color()),
+                    (" is about to break!".into(), Color::Yellow),
+                ]);
+            }
+        }
+    }
+
+    pub fn get_craft_status(&self, a: &Rc<ItemInfo>, b: &Rc<ItemInfo>) -> CraftStatus {
+        self.craft_statuses
+            .get(&(a.clone(), b.clone()))
+            .copied()
+            .unwrap_or(CraftStatus::Queued)
+    }
+
+    /// Recipes for the recipe book, as (reagent, reagent, result).
+    pub fn get_recipe_book(&self) -> Vec<(Rc<ItemInfo>, Rc<ItemInfo>, Rc<ItemInfo>)> {
+        if self.rules.recipe_book_shows_all {
+            let mut all = self
+                .world_info
+                .recipes
+                .iter()
+                .map(|((a, b), c)| (a.clone(), b.clone(), c.clone()))
+                .collect::<Vec<_>>();
+            all.sort_by(|x, y| (&x.0.name, &x.1.name).cmp(&(&y.0.name, &y.1.name)));
+            all
+        } else {
+            self.discovered_recipes
+                .iter()
+                .map(|((a, b), c)| (a.clone(), b.clone(), c.clone()))
+                .collect()
         }
     }
 
+    fn discover_recipe(&mut self, a: Rc<ItemInfo>, b: Rc<ItemInfo>, c: Rc<ItemInfo>) {
+        self.discovered_recipes.insert((a, b), c);
+    }
+
     pub fn log_message(&mut self, text: Vec<(String, Color)>) {
         println!(
             "{}",
@@ -830,6 +1297,7 @@ impl World {
     fn damage_mob(&mut self, mut mob: Mob, mob_pos: Pos, damage: usize, eff: AttackEffectiveness) {
         let mki = self.get_mobkind_info(mob.kind).clone();
         mob.damage += damage;
+        self.untriggered_sounds.push(SoundEffect::Attack);
 
         let mut msg = vec![
             ("You hit ".into(), Color::White),
@@ -841,24 +1309,158 @@ impl World {
         self.log_message(msg);
         if mob.damage >= mki.max_hp() {
             self.log_message(vec![(mki.death, mki.color)]);
-            if mob.kind == self.world_info.boss_info.as_ref().unwrap().mob_kind {
+            self.defeated.insert(mob.kind);
+            if self.tile_map[mob_pos].kind == TileKind::Floor {
+                self.tile_map[mob_pos].kind = TileKind::BloodyFloor;
+            }
+            if self.world_info.is_final_boss(mob.kind) {
                 self.victory = true;
+                self.untriggered_sounds.push(SoundEffect::Victory);
                 self.log_message(vec![("YOU WIN!".into(), Color::Gold)]);
+                if self.rules.free_roam_after_victory {
+                    self.log_message(vec![(
+                        "You are free to explore what remains of the dungeon.".into(),
+                        Color::White,
+                    )]);
+                }
             }
         } else {
             self.mobs.insert(mob_pos, mob);
         }
     }
 
+    /// Has a looting mob take the item at pos, if any, and get stronger from it.
+    fn mob_loot(&mut self, mob: &mut Mob, pos: Pos, visible: bool) {
+        let ii = match self.tile_map[pos].item {
+            // Uniques might be needed to unlock stairs, so leave them be.
+            Some(Item::Instance(ref ii)) if !ii.info.unique => ii.clone(),
+            _ => return,
+        };
+        self.tile_map[pos].item = None;
+        match ii.info.kind {
+            ItemKind::MeleeWeapon | ItemKind::RangedWeapon => mob.attack_bonus += 1,
+            ItemKind::Armor => mob.defense_bonus += 1,
+            ItemKind::Food => {
+                let heal = ii.info.get_heal_amount(&[]).max(0) as usize;
+                mob.damage = mob.damage.saturating_sub(heal);
+            }
+        }
+        if visible {
+            let mki = self.get_mobkind_info(mob.kind);
+            let msg = vec![
+                (mki.name.clone(), mki.color),
+                (" picks up the ".into(), Color::White),
+                (ii.info.name.clone(), ii.info.ty.get_color()),
+                ("!".into(), Color::White),
+            ];
+            self.log_message(msg);
+        }
+    }
+
+    /// Whether the current level's stairs lead down. Logs why if they don't.
+    fn check_stairs_lock(&mut self) -> bool {
+        let lock = self
+            .world_info
+            .areas
+            .get(self.level_id)
+            .and_then(|area| area.stairs_lock.clone());
+        let (unlocked, msg) = match lock {
+            None => return true,
+            Some(StairsLock::Defeat(name)) => {
+                match self.world_info.get_defeat_lock_kind(self.level_id, &name) {
+                    Some(kind) => (
+                        self.defeated.contains(&kind),
+                        format!(
+                            "The way down stays shut until {} is defeated.",
+                            self.get_mobkind_info(kind).name
+                        ),
+                    ),
+                    // Nothing by that name exists. Leave it open rather than strand the player.
+                    None => return true,
+                }
+            }
+            Some(StairsLock::Carry(name)) => (
+                self.inventory.items.iter().any(
+                    |ii| matches!(ii.item, Item::Instance(ref inst) if inst.info.name == name),
+                ),
+                format!("The way down stays shut without {name}."),
+            ),
+        };
+        if !unlocked {
+            self.log_message(vec![(msg, Color::White)]);
+        }
+        unlocked
+    }
+
+    /// Crafts inventory items i and j together. Returns whether it worked.
+    fn craft_pair(&mut self, i: usize, j: usize) -> bool {
+        if i == j {
+            false
+        } else if let Some(item1) = self.inventory.get(i) {
+            if let Some(item2) = self.inventory.get(j) {
+                let max_diff = self.rules.max_craft_level_diff();
+                match self
+                    .world_info
+                    .craft(item1.clone(), item2.clone(), max_diff)
+                {
+                    Ok(new_item) => {
+                        self.inventory.remove_all(vec![i, j]);
+                        if let (
+                            Item::Instance(ref a),
+                            Item::Instance(ref b),
+                            Item::Instance(ref c),
+                        ) = (item1, item2, new_item.clone())
+                        {
+                            self.log_message(Self::get_craft_msg(
+                                a.info.clone(),
+                                b.info.clone(),
+                                c.info.clone(),
+                            ));
+                            self.discover_recipe(a.info.clone(), b.info.clone(), c.info.clone());
+                        }
+
+                        self.inventory.add(new_item);
+                        true
+                    }
+                    Err(e) => {
+                        let msg = match e {
+                            CraftError::BadLevel if self.rules.craft_adjacent_levels => vec![
+                                ("Crafting ingredients must be ".into(), Color::White),
+                                ("at most one level apart".into(), Color::Yellow),
+                            ],
+                            CraftError::BadLevel => vec![
+                                ("Crafting ingredients must be the ".into(), Color::White),
+                                ("same level".into(), Color::Yellow),
+                            ],
+                            CraftError::ReagentPending => vec![
+                                ("Selected ingredient is ".into(), Color::White),
+                                ("still getting crafted".to_string(), Color::Yellow),
+                            ],
+                        };
+                        self.log_message(msg);
+                        false
+                    }
+                }
+            } else {
+                false
+            }
+        } else {
+            false
+        }
+    }
+
     pub fn do_player_action(&mut self, action: PlayerAction) -> bool {
-        if self.player_is_dead() || self.victory {
+        if self.player_is_dead() || (self.victory && !self.rules.free_roam_after_victory) {
             return false;
         }
         let tick = match action {
             PlayerAction::Move(offset) => {
                 assert!(offset.mhn_dist() == 1);
                 let new_pos = self.player_pos + offset;
-                if let Some(mob) = self.mobs.remove(&new_pos) {
+                if self.victory && self.mobs.contains_key(&new_pos) {
+                    // No more fighting after the boss is dead.
+                    false
+                } else if let Some(mob) = self.mobs.remove(&new_pos) {
                     let mki = self.get_mobkind_info(mob.kind).clone();
                     let player_weapon_info = self.inventory.get_equipped_weapon_info(true);
                     let (att_type, att_level) = player_weapon_info
@@ -866,7 +1468,17 @@ impl World {
                         .map(|w| (w.ty, w.level))
                         .unwrap_or((PokemonType::Normal, 0));
                     let eff = att_type.get_effectiveness2(mki.type1, mki.type2);
-                    let damage = calc_damage(att_level, mki.level, eff, true, false);
+                    let stab = player_weapon_info
+                        .as_ref()
+                        .is_some_and(|w| self.player_has_stab(w));
+                    let damage = self.combat.calc_damage(
+                        att_level,
+                        mki.level + mob.defense_bonus,
+                        eff,
+                        stab,
+                        true,
+                        false,
+                    );
 
                     self.damage_mob(mob, new_pos, damage, eff);
 
@@ -880,6 +1492,7 @@ impl World {
                             (" breaks!".into(), Color::Red),
                         ]);
                     }
+                    self.warn_about_to_break(ItemKind::MeleeWeapon);
 
                     true
                 } else if self.tile_map[new_pos].kind.is_walkable() {
@@ -899,13 +1512,21 @@ impl World {
                         self.log_message(msg);
                     }
 
-                    if let Some(dest) = self.stairs.get(&new_pos) {
-                        self.player_pos = *dest;
-                        self.mobs.remove(dest);
+                    let stairs = self.stairs.get(&new_pos).copied();
+                    let unlocked = stairs.is_some() && self.check_stairs_lock();
+                    if let Some(dest) = stairs.filter(|_| unlocked) {
+                        self.player_pos = dest;
+                        self.mobs.remove(&dest);
                         self.level_id += 1;
+                        self.untriggered_sounds.push(SoundEffect::Descend);
+                        self.untriggered_animations.push(AnimationState::new(
+                            Animation::LevelTransition,
+                            LEVEL_TRANSITION_DURATION,
+                        ));
                         if let Some(blurb) = self.world_info.level_blurbs.get(self.level_id) {
                             self.log_message(vec![(blurb.clone(), Color::White)]);
                         }
+                        self.reclaim_stale_crafts();
                     } else {
                         self.player_pos += offset;
                     }
@@ -916,19 +1537,16 @@ impl World {
             }
             PlayerAction::Fire(direction) => {
                 assert!(direction.mhn_dist() == 1);
-                if let Some(pwi) = self.inventory.get_equipped_weapon_info(false) {
+                if self.victory {
+                    false
+                } else if let Some(pwi) = self.inventory.get_equipped_weapon_info(false) {
                     let range = pwi.get_range() as i32;
                     let start_pos = self.player_pos;
                     let end_pos = self.player_pos + direction * range;
                     let mut zapped_tiles = Vec::new();
-                    for (x, y) in line_drawing::Bresenham::new(
-                        (start_pos.x, start_pos.y),
-                        (end_pos.x, end_pos.y),
-                    )
-                    .skip(0)
+                    for zapped_pos in line_drawing::Bresenham::new(start_pos.into(), end_pos.into())
+                        .map(Pos::from)
                     {
-                        let zapped_pos = Pos::new(x, y);
-
                         // Stop if the projectile hits a wall.
                         if !self.tile_map[zapped_pos].kind.is_walkable() {
                             break;
@@ -937,12 +1555,21 @@ impl World {
                             let mki = self.get_mobkind_info(mob.kind).clone();
                             let (att_type, att_level) = (pwi.ty, pwi.level);
                             let eff = att_type.get_effectiveness2(mki.type1, mki.type2);
-                            let damage = calc_damage(att_level, mki.level, eff, true, true);
+                            let stab = self.player_has_stab(&pwi);
+                            let damage = self.combat.calc_damage(
+                                att_level,
+                                mki.level + mob.defense_bonus,
+                                eff,
+                                stab,
+                                true,
+                                true,
+                            );
                             self.damage_mob(mob, zapped_pos, damage, eff);
                         }
                         zapped_tiles.push(zapped_pos);
                     }
 
+                    self.noises.push((self.player_pos, FIRE_NOISE_RADIUS));
                     self.untriggered_animations.push(AnimationState::new(
                         Animation::Shot(ShotAnimation {
                             cells: zapped_tiles,
@@ -963,6 +1590,7 @@ impl World {
                             (format!(" runs out of ammo and {breaks}!"), Color::Red),
                         ]);
                     }
+                    self.warn_about_to_break(ItemKind::RangedWeapon);
                     true
                 } else {
                     self.log_message(vec![(
@@ -988,6 +1616,7 @@ impl World {
                             self.get_item_log_message(&item),
                         ]);
                     }
+                    self.untriggered_sounds.push(SoundEffect::PickUp);
                     true
                 } else {
                     false
@@ -1055,70 +1684,89 @@ impl World {
                 }
             }
             PlayerAction::Wait => true,
-            PlayerAction::Craft(i, j) => {
-                if i == j {
-                    false
-                } else if let Some(item1) = self.inventory.get(i) {
-                    if let Some(item2) = self.inventory.get(j) {
-                        match self.world_info.craft(item1.clone(), item2.clone()) {
-                            Ok(new_item) => {
-                                self.inventory.remove_all(vec![i, j]);
-                                if let (
-                                    Item::Instance(ref a),
-                                    Item::Instance(ref b),
-                                    Item::Instance(ref c),
-                                ) = (item1, item2, new_item.clone())
-                                {
-                                    self.log_message(Self::get_craft_msg(
-                                        a.info.clone(),
-                                        b.info.clone(),
-                                        c.info.clone(),
-                                    ));
-                                }
-
-                                self.inventory.add(new_item);
-                                true
-                            }
-                            Err(e) => {
-                                let msg = match e {
-                                    CraftError::BadLevel => vec![
-                                        ("Crafting ingredients must be the ".into(), Color::White),
-                                        ("same level".into(), Color::Yellow),
-                                    ],
-                                    CraftError::ReagentPending => vec![
-                                        ("Selected ingredient is ".into(), Color::White),
-                                        ("still getting crafted".to_string(), Color::Yellow),
-                                    ],
-                                };
-                                self.log_message(msg);
-                                false
-                            }
-                        }
-                    } else {
-                        false
+            PlayerAction::Craft(mut indices) => {
+                // Craft the first two, then keep folding the result into the
+                // next item until something fails.
+                let mut tick = false;
+                while indices.len() >= 2 {
+                    let (a, b) = (indices[0], indices[1]);
+                    if !self.craft_pair(a, b) {
+                        break;
                     }
-                } else {
-                    false
+                    if !tick {
+                        self.noises.push((self.player_pos, CRAFT_NOISE_RADIUS));
+                    }
+                    tick = true;
+                    // The result goes at the end. Stop if it's still waiting on a recipe.
+                    let result = self.inventory.items.len() - 1;
+                    if !matches!(self.inventory.items[result].item, Item::Instance(_)) {
+                        break;
+                    }
+                    // Both reagents are gone, so later items shift down.
+                    let mut rest = indices.split_off(2);
+                    for i in rest.iter_mut() {
+                        *i -= [a, b].iter().filter(|&&j| j < *i).count();
+                    }
+                    indices = std::iter::once(result).chain(rest).collect();
                 }
+                tick
             }
         };
-        self.inventory.sort();
-        if tick {
+        self.inventory.arrange();
+        // Monsters stand down once the game is won.
+        if tick && !self.victory {
             self.tick();
         }
+        self.noises.clear();
         tick
     }
 
+    /// The player gets a same-type attack bonus when the weapon's secondary
+    /// type or any equipped armor shares the weapon's type.
+    fn player_has_stab(&self, weapon: &ItemInfo) -> bool {
+        if !self.rules.stab {
+            return false;
+        }
+        let player_types = self
+            .inventory
+            .get_equipped_armor_info()
+            .iter()
+            .map(|a| a.ty)
+            .chain(weapon.ty2)
+            .collect::<Vec<_>>();
+        weapon.ty.is_stab(&player_types)
+    }
+
+    /// Finds the first step from start towards end. See `path_full`.
     pub fn path(
-        &mut self,
+        &self,
         start: Pos,
         end: Pos,
         maxdist: usize,
         through_walls: bool,
         around_mobs: bool,
     ) -> Option<Offset> {
+        let path = self.path_full(start, end, maxdist, through_walls, around_mobs)?;
+        if path.len() >= 2 {
+            Some(path[1] - path[0])
+        } else {
+            Some(Offset { x: 0, y: 0 })
+        }
+    }
+
+    /// Finds every position from start to end, or to the closest reachable
+    /// position if end can't be reached. Neighbors are visited in
+    /// `Pos::adjacent_cardinal` order, so ties always break the same way.
+    pub fn path_full(
+        &self,
+        start: Pos,
+        end: Pos,
+        maxdist: usize,
+        through_walls: bool,
+        around_mobs: bool,
+    ) -> Option<Vec<Pos>> {
         if start == end {
-            return Some(Offset { x: 0, y: 0 });
+            return Some(vec![start]);
         }
         let mut visited = HashSet::new();
         let mut periphery = Vec::new();
@@ -1126,19 +1774,9 @@ impl World {
         visited.insert(start);
         periphery.push(vec![start]);
         let mut closest_path: Option<Vec<_>> = None;
-        let mut cardinals_shuffled = CARDINALS;
-        cardinals_shuffled.shuffle(&mut self.rng);
         loop {
             if periphery.is_empty() || periphery[0].len() > maxdist {
-                return if let Some(ref p) = closest_path {
-                    if p.len() >= 2 {
-                        Some(p[1] - p[0])
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
+                return closest_path;
             }
             for path in periphery.drain(..) {
                 let pos = *path.last().unwrap();
@@ -1154,7 +1792,7 @@ impl World {
                     let mut new_path = path.clone();
                     new_path.push(pos);
                     if pos == end {
-                        return Some(new_path[1] - new_path[0]);
+                        return Some(new_path);
                     }
                     match closest_path {
                         None => {
@@ -1174,8 +1812,23 @@ impl World {
         }
     }
 
+    /// When allies block every way towards dest, the tile of the one in the way if
+    /// it should trade places. Idle allies always make way. Moving ones only do if
+    /// the trade brings them closer to their own destination, so two mobs can't
+    /// keep swapping back and forth.
+    fn find_swap(&self, pos: Pos, dest: Pos) -> Option<Pos> {
+        let ahead = pos + self.path(pos, dest, FOV_RANGE as usize * 3, false, false)?;
+        let other = self.mobs.get(&ahead)?;
+        match other.ai {
+            MobAi::Idle => Some(ahead),
+            MobAi::Move { dest: other_dest } => {
+                ((pos - other_dest).mhn_dist() < (ahead - other_dest).mhn_dist()).then_some(ahead)
+            }
+        }
+    }
+
     fn path_towards(
-        &mut self,
+        &self,
         pos: Pos,
         target: Pos,
         through_walls: bool,
@@ -1196,12 +1849,115 @@ impl World {
         }
     }
 
+    /// Turns the player has taken. `step` starts at 1.
+    pub fn turns_taken(&self) -> usize {
+        self.step - 1
+    }
+
+    pub fn level_id(&self) -> usize {
+        self.level_id
+    }
+
+    /// Empties an area so a level can be generated there again: its tiles,
+    /// monsters and stairs. Returns where those stairs led.
+    pub fn clear_area(&mut self, area: grid::Rect) -> Vec<Pos> {
+        for pos in area {
+            self[pos] = Tile {
+                kind: TileKind::Wall,
+                item: None,
+            };
+        }
+        self.mobs.retain(|pos, _| !area.contains(*pos));
+        let exits = self
+            .stairs
+            .iter()
+            .filter(|(pos, _)| area.contains(**pos))
+            .map(|(_, dest)| *dest)
+            .collect();
+        self.stairs.retain(|pos, _| !area.contains(*pos));
+        exits
+    }
+
+    /// Points every staircase that led into `area` at `dest` instead.
+    pub fn retarget_stairs(&mut self, area: grid::Rect, dest: Pos) {
+        for stairs_dest in self.stairs.values_mut() {
+            if area.contains(*stairs_dest) {
+                *stairs_dest = dest;
+            }
+        }
+    }
+
+    pub fn current_level_seed(&self) -> Option<u64> {
+        self.level_seeds.get(self.level_id).copied()
+    }
+
+    /// Whether pos blocks line of sight, either by its tile or a large mob on it.
+    pub fn is_opaque(&self, pos: Pos) -> bool {
+        self.tile_map[pos].kind.is_opaque()
+            || self
+                .mobs
+                .get(&pos)
+                .is_some_and(|mob| self.get_mobkind_info(mob.kind).blocks_sight)
+    }
+
+    /// Everything in sight of the player, lit or not. Monsters use this to
+    /// spot and shoot the player, so darkness doesn't change their range.
+    pub fn get_line_of_sight(&self) -> HashSet<Pos> {
+        crate::fov::calculate_fov_with(self.player_pos, FOV_RANGE, |pos| self.is_opaque(pos))
+    }
+
+    /// What the player can see. In unlit areas, that's only the lit tiles in
+    /// sight, so carried lights and torches let the player see further.
     pub fn get_fov(&self) -> HashSet<Pos> {
-        crate::fov::calculate_fov(self.player_pos, FOV_RANGE, self)
+        let mut fov = self.get_line_of_sight();
+        let unlit = self
+            .world_info
+            .areas
+            .get(self.level_id)
+            .is_some_and(|area| area.unlit);
+        if unlit {
+            let size = FOV_RANGE * 2 + 1;
+            let light = self.get_light_map(grid::Rect::new_centered(self.player_pos, size, size));
+            fov.retain(|pos| light.get(pos).is_some_and(|&b| b > 0.0));
+        }
+        fov
+    }
+
+    /// Returns (position, radius) of every light source that could reach into the given area.
+    pub fn get_light_sources(&self, area: grid::Rect) -> Vec<(Pos, i32)> {
+        let carried_light = self
+            .inventory
+            .items
+            .iter()
+            .filter(|x| x.equipped)
+            .filter_map(|x| match x.item {
+                Item::Instance(ref ii) => Some(ii.info.get_light_radius()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let mut sources = vec![(self.player_pos, PLAYER_LIGHT_RADIUS.max(carried_light))];
+        let max_tile_light = TILE_INFOS
+            .values()
+            .map(|info| info.light)
+            .max()
+            .unwrap_or(0);
+        for pos in area.expand(max_tile_light) {
+            let radius = self.tile_map[pos].kind.light_radius();
+            if radius > 0 {
+                sources.push((pos, radius));
+            }
+        }
+        sources
+    }
+
+    pub fn get_light_map(&self, area: grid::Rect) -> HashMap<Pos, f32> {
+        crate::fov::calculate_light(&self.get_light_sources(area), self)
     }
 
-    pub fn get_visible_mobs(&self) -> Vec<Mob> {
-        let fov = crate::fov::calculate_fov(self.player_pos, FOV_RANGE, self);
+    /// Visible mobs and where they are, nearest first.
+    pub fn get_visible_mobs_with_pos(&self) -> Vec<(Pos, Mob)> {
+        let fov = self.get_fov();
         let mut all_mobs: Vec<(i32, Pos, Mob)> = Vec::new();
         for pos in fov {
             if self.mobs.contains_key(&pos) {
@@ -1214,31 +1970,48 @@ impl World {
         }
 
         all_mobs.sort_by_key(|(dist_sq, pos, _)| (*dist_sq, pos.x, pos.y));
-        all_mobs.iter().map(|(_, _, mob)| mob.clone()).collect()
+        all_mobs
+            .into_iter()
+            .map(|(_, pos, mob)| (pos, mob))
+            .collect()
     }
 
     pub fn tick(&mut self) {
-        let poses = self.mobs.keys().copied().collect::<Vec<_>>();
-        let boss_kind = self.world_info.boss_info.as_ref().unwrap().mob_kind;
-        let fov = crate::fov::calculate_fov(self.player_pos, FOV_RANGE, self);
+        let mut poses = self.mobs.keys().copied().collect::<Vec<_>>();
+        // HashMap order varies between runs; sort so input replays play out the same.
+        poses.sort_by_key(|pos| (pos.y, pos.x));
+        let mut fov = self.get_line_of_sight();
+        // Mobs that already acted this tick, in case a swap moved them onto a later position.
+        let mut moved = HashSet::new();
         for pos in poses {
             let mut mob = match self.mobs.remove(&pos) {
+                Some(mob) if moved.contains(&mob.id) => {
+                    self.mobs.insert(pos, mob);
+                    continue;
+                }
                 Some(mob) => mob,
                 None => continue,
             };
+            moved.insert(mob.id);
             let mki = self.get_mobkind_info(mob.kind).clone();
-            if mob.kind == boss_kind && fov.contains(&pos) && self.rng.gen::<f64>() < 0.1 {
-                if let Some(msg) = self
-                    .world_info
-                    .boss_info
-                    .as_ref()
-                    .unwrap()
-                    .periodic_messages
-                    .choose(&mut self.rng)
-                {
+            let boss_msgs = self
+                .world_info
+                .get_boss_info(mob.kind)
+                .map(|b| b.periodic_messages.clone())
+                .unwrap_or_default();
+            if !boss_msgs.is_empty() && fov.contains(&pos) && self.rng.gen::<f64>() < 0.1 {
+                if let Some(msg) = boss_msgs.choose(&mut self.rng) {
                     self.log_message(vec![(msg.clone(), Color::White)]);
                 }
             }
+            if matches!(mob.ai, MobAi::Idle) {
+                let heard = self.noises.iter().find(|&&(noise_pos, radius)| {
+                    (noise_pos - pos).dist_squared() <= radius * radius
+                });
+                if let Some(&(noise_pos, _)) = heard {
+                    mob.ai = MobAi::Move { dest: noise_pos };
+                }
+            }
             let mut current_pos = pos;
             while mob.actions >= SPEED_MUL {
                 if fov.contains(&current_pos) {
@@ -1258,17 +2031,39 @@ impl World {
                     MobAi::Idle => current_pos = pos,
                     MobAi::Move { dest } => {
                         // Start by determining the next position we want to move towards.
-                        let target = self.path_towards(current_pos, dest, false, true, None);
+                        let mut target = self.path_towards(current_pos, dest, false, true, None);
+                        // Boxed in by allies: trade places with one if that doesn't set it back.
+                        // In a corridor the way around them may lead backwards, so this
+                        // kicks in whenever the step gets no closer, not only when stuck.
+                        let mut swap = false;
+                        if (target - dest).mhn_dist() >= (current_pos - dest).mhn_dist() {
+                            if let Some(ahead) = self.find_swap(current_pos, dest) {
+                                target = ahead;
+                                swap = true;
+                            }
+                        }
 
+                        // Every worn piece counts, its type toward effectiveness and
+                        // its level toward defense. Unarmored, the player is Normal.
                         let armor = self.inventory.get_equipped_armor_info();
-                        let defense1 = armor
-                            .first()
-                            .map(|eki| eki.ty)
-                            .unwrap_or(PokemonType::Normal);
-                        let defense2 = armor.get(1).map(|eki| eki.ty);
-                        let eff = mki.attack_type.get_effectiveness2(defense1, defense2);
+                        let mut defense_types = armor.iter().map(|a| a.ty).collect::<Vec<_>>();
+                        if defense_types.is_empty() {
+                            defense_types.push(PokemonType::Normal);
+                        }
+                        let eff = mki.attack_type.get_effectiveness_all(&defense_types);
                         let def_level = armor.iter().map(|a| a.level).sum();
-                        let damage = calc_damage(mki.level, def_level, eff, false, true);
+                        let mob_types = std::iter::once(mki.type1)
+                            .chain(mki.type2)
+                            .collect::<Vec<_>>();
+                        let stab = self.rules.stab && mki.attack_type.is_stab(&mob_types);
+                        let damage = self.combat.calc_damage(
+                            mki.level + mob.attack_bonus,
+                            def_level,
+                            eff,
+                            stab,
+                            false,
+                            true,
+                        );
                         let range = (5 + mki.level * 2) as i32;
                         let in_range =
                             (current_pos - self.player_pos).dist_squared() <= range * range;
@@ -1278,19 +2073,48 @@ impl World {
 
                         // If ranged and in range and reload cooldown done
                         let mut can_fire = mki.ranged && in_range && mob.reload == 0;
+                        // Shots leave from where the mob stands now, before it steps.
                         let fire_line: Vec<_> = line_drawing::Bresenham::new(
-                            (target.x, target.y),
-                            (self.player_pos.x, self.player_pos.y),
+                            current_pos.into(),
+                            self.player_pos.into(),
                         )
-                        .map(|(x, y)| Pos::new(x, y))
+                        .map(Pos::from)
                         .collect();
 
                         // If we can't see it, also avoid it. Or if there's friendly fire.
+                        // Only mobs between the shooter and the player are in the way.
                         can_fire &= fov.contains(&current_pos);
-                        can_fire &= !fire_line.iter().any(|&pos| self.mobs.contains_key(&pos));
+                        can_fire &= !fire_line
+                            .iter()
+                            .skip(1)
+                            .any(|pos| self.mobs.contains_key(pos));
                         // If melee and adjacent, then let fire.
                         can_fire |= !mki.ranged && target == self.player_pos;
 
+                        // Ranged mobs take aim a turn before firing, so the player can react.
+                        if mki.ranged {
+                            match mob.aiming_since {
+                                _ if !can_fire => mob.aiming_since = None,
+                                Some(step) if step < self.step => mob.aiming_since = None,
+                                Some(_) => can_fire = false,
+                                None => {
+                                    mob.aiming_since = Some(self.step);
+                                    can_fire = false;
+                                    self.log_message(vec![
+                                        (mki.name.clone(), mki.color),
+                                        (" takes aim!".into(), Color::White),
+                                    ]);
+                                    self.untriggered_animations.push(AnimationState::new(
+                                        Animation::Aim(ShotAnimation {
+                                            cells: fire_line.clone(),
+                                            color: mki.attack_type.get_color(),
+                                        }),
+                                        0.5,
+                                    ));
+                                }
+                            }
+                        }
+
                         if can_fire {
                             let msg = mki.attack.choose(&mut self.rng).unwrap().clone();
                             let mut log_msg = vec![
@@ -1311,6 +2135,7 @@ impl World {
                                     (" breaks!".into(), Color::Red),
                                 ]);
                             }
+                            self.warn_about_to_break(ItemKind::Armor);
 
                             if mki.ranged {
                                 self.untriggered_animations.push(AnimationState::new(
@@ -1324,14 +2149,26 @@ impl World {
                             }
 
                             self.player_damage += damage;
+                            self.untriggered_sounds.push(SoundEffect::Hurt);
                         }
 
-                        if target != self.player_pos {
+                        // Hold still while aiming so the line of fire stays put.
+                        if target != self.player_pos && mob.aiming_since.is_none() {
+                            if swap {
+                                if let Some(other) = self.mobs.remove(&target) {
+                                    // Being shoved aside uses up the ally's turn.
+                                    moved.insert(other.id);
+                                    self.mobs.insert(current_pos, other);
+                                }
+                            }
                             current_pos = target;
+                            if self.rules.mobs_loot_items && mki.loots {
+                                self.mob_loot(&mut mob, current_pos, fov.contains(&current_pos));
+                            }
                         }
                     }
                 }
-                if mob.reload != 0 {
+                if mki.ranged && mob.reload != 0 {
                     mob.reload -= 1;
                 }
 
@@ -1342,9 +2179,27 @@ impl World {
             }
             mob.actions += self.get_mobkind_info(mob.kind).speed.get_actions_per_turn();
             self.mobs.insert(current_pos, mob);
+            // A sight-blocking mob moving changes which other mobs can see the player.
+            if mki.blocks_sight && current_pos != pos {
+                for changed in [pos, current_pos] {
+                    crate::fov::update_fov_with(
+                        &mut fov,
+                        self.player_pos,
+                        FOV_RANGE,
+                        changed,
+                        |p| self.is_opaque(p),
+                    );
+                }
+            }
+        }
+        if self.tile_map[self.player_pos].kind == TileKind::Hazard && !self.player_is_dead() {
+            self.player_damage += HAZARD_DAMAGE;
+            self.log_message(vec![("The ground here hurts you!".into(), Color::Red)]);
+            self.untriggered_sounds.push(SoundEffect::Hurt);
         }
         if self.player_is_dead() {
             self.log_message(vec![("YOU DIED".into(), Color::Red)]);
+            self.untriggered_sounds.push(SoundEffect::Death);
         }
         self.step += 1;
     }
@@ -1365,18 +2220,87 @@ impl World {
         self.mobs.get(&pos).cloned()
     }
 
-    pub fn add_mob(&mut self, pos: grid::Pos, mob: Mob) {
+    pub fn add_mob(&mut self, pos: grid::Pos, mut mob: Mob) {
+        mob.id = self.next_mob_id;
+        self.next_mob_id += 1;
         self.mobs.insert(pos, mob);
     }
 
     pub fn get_mobkind_info(&self, kind: MobKind) -> &MobKindInfo {
         self.world_info.get_mobkind_info(kind)
     }
+
+    /// Describes the item underfoot, or else the closest monster in view.
+    pub fn inspect_surroundings(&mut self) {
+        let msg = if let Some(Item::Instance(ref ii)) = self.tile_map[self.player_pos].item {
+            (
+                format!("{}: {}", ii.info.name, ii.info.description),
+                ii.info.ty.get_color(),
+            )
+        } else if let Some((pos, mob)) = self.get_visible_mobs_with_pos().first() {
+            let mki = self.get_mobkind_info(mob.kind);
+            let dir = (*pos - self.player_pos).compass_name();
+            (
+                format!("{} (to the {dir}): {}", mki.name, mki.description),
+                mki.color,
+            )
+        } else {
+            ("There's nothing interesting here.".into(), Color::White)
+        };
+        self.log_message(vec![msg]);
+    }
+
+    /// Plain-text summary of what the player can currently see, for players
+    /// who can't make out the map.
+    pub fn describe_view(&self, memory: &Memory) -> Vec<String> {
+        let player_hp = PLAYER_MAX_HEALTH.saturating_sub(self.player_damage);
+        let mut lines = vec![format!(
+            "You are at {}, {} on level {}. HP {}/{}.",
+            self.player_pos.x,
+            self.player_pos.y,
+            self.level_id + 1,
+            player_hp,
+            PLAYER_MAX_HEALTH
+        )];
+
+        let mut mobs = memory.mobs.iter().collect::<Vec<_>>();
+        mobs.sort_by_key(|(pos, _)| ((**pos - self.player_pos).mhn_dist(), pos.x, pos.y));
+        if mobs.is_empty() {
+            lines.push("No monsters in sight.".into());
+        }
+        for (&pos, mob) in mobs {
+            let mki = self.get_mobkind_info(mob.kind);
+            let offset = pos - self.player_pos;
+            let max_hp = mki.max_hp();
+            lines.push(format!(
+                "{}: {} steps {}, HP {}/{}.",
+                mki.name,
+                offset.mhn_dist(),
+                offset.compass_name(),
+                max_hp.saturating_sub(mob.damage),
+                max_hp
+            ));
+        }
+
+        if let Some(ref item) = self.tile_map[self.player_pos].item {
+            lines.push(format!("Here: {}.", self.get_item_log_message(item).0));
+        }
+
+        for (message, _step) in self.log.iter().rev().take(5).rev() {
+            lines.push(message.iter().map(|(s, _)| s.as_str()).collect());
+        }
+        lines
+    }
 }
 
 pub struct Memory {
     pub tile_map: TileMap<Option<Tile>>,
     pub mobs: HashMap<Pos, Mob>,
+    /// Tiles the player could see as of the last update.
+    pub fov: HashSet<Pos>,
+    /// Light across the view as of the last update, so rendering needn't
+    /// recompute it every frame.
+    pub light: HashMap<Pos, f32>,
 }
 
 impl Memory {
@@ -1384,6 +2308,31 @@ impl Memory {
         Self {
             tile_map: TileMap::new(None),
             mobs: HashMap::new(),
+            fov: HashSet::new(),
+            light: HashMap::new(),
+        }
+    }
+
+    /// Mobs seen in the last update, nearest to `player_pos` first.
+    pub fn visible_mobs(&self, player_pos: Pos) -> Vec<Mob> {
+        let mut mobs = self.mobs.iter().collect::<Vec<_>>();
+        mobs.sort_by_key(|(pos, _)| ((player_pos - **pos).dist_squared(), pos.x, pos.y));
+        mobs.into_iter().map(|(_, mob)| mob.clone()).collect()
+    }
+
+    /// Remembers everything the player can currently see. Visible tiles are
+    /// overwritten wholesale, so an item that's gone from a visible tile is
+    /// forgotten, while items on tiles out of view stay as they were last seen.
+    pub fn update(&mut self, world: &World) {
+        self.mobs.clear();
+        self.fov = world.get_fov();
+        let view = grid::Rect::new_centered(world.player_pos, VIEW_SIZE, VIEW_SIZE);
+        self.light = world.get_light_map(view);
+        for &pos in &self.fov {
+            self.tile_map[pos] = Some(world.get_tile(pos));
+            if let Some(mob) = world.get_mob(pos) {
+                self.mobs.insert(pos, mob);
+            }
         }
     }
 }
@@ -1401,3 +2350,980 @@ impl std::ops::IndexMut<Pos> for World {
         self.tile_map.index_mut(pos)
     }
 }
+
+impl World {
+    /// Modifies a tile without allocating a chunk for default-valued writes.
+    pub fn modify_tile(&mut self, pos: Pos, f: impl FnOnce(&mut Tile)) {
+        self.tile_map.modify(pos, f)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::net::MapGen;
+
+    pub(crate) fn monster(name: &str, level: usize, ranged: bool) -> MonsterDefinition {
+        MonsterDefinition {
+            name: name.into(),
+            char: name[..1].into(),
+            color: Color::White,
+            attack_type: PokemonType::Normal,
+            type1: PokemonType::Normal,
+            type2: None,
+            description: format!("A {name}."),
+            level,
+            seen: format!("A {name} appears."),
+            attack: format!("The {name} attacks."),
+            death: format!("The {name} dies."),
+            ranged,
+            speed: 2,
+            blocks_sight: false,
+            loots: false,
+        }
+    }
+
+    pub(crate) fn item(name: &str, level: usize, kind: ItemKind) -> ItemDefinition {
+        ItemDefinition {
+            name: name.into(),
+            level,
+            ty: PokemonType::Normal,
+            description: format!("A {name}."),
+            kind,
+            craft_id: None,
+            unique: false,
+            slot: None,
+        }
+    }
+
+    pub(crate) fn area(name: &str) -> Area {
+        Area {
+            name: name.into(),
+            blurb: format!("You enter the {name}."),
+            mapgen: MapGen::SimpleRoomsAndCorridors,
+            enemies: vec!["rat".into(), "archer".into()],
+            equipment: vec!["mail".into()],
+            melee_weapons: vec!["sword".into()],
+            ranged_weapons: vec!["bow".into()],
+            food: vec!["bread".into()],
+            stairs_lock: None,
+            enemy_level_weights: None,
+            mini_boss: None,
+            unlit: false,
+        }
+    }
+
+    /// A world built from hand-written definitions, without the server: a
+    /// rat and an archer, a few items, the fallback boss, and an open 20x10
+    /// room with the player near its left edge and nothing else in it.
+    pub(crate) fn test_world() -> World {
+        test_world_in(&[area("cellar")])
+    }
+
+    /// Like `test_world`, but with these areas.
+    pub(crate) fn test_world_in(areas: &[Area]) -> World {
+        let mut world = World::new();
+        world.world_info.add_definitions(
+            areas,
+            &[
+                item("sword", 1, ItemKind::MeleeWeapon),
+                item("bow", 1, ItemKind::RangedWeapon),
+                item("mail", 1, ItemKind::Armor),
+                item("bread", 1, ItemKind::Food),
+            ],
+            &[monster("rat", 1, false), monster("archer", 1, true)],
+            &crate::net::fallback_boss(),
+        );
+        for pos in grid::Rect::new(0, 19, 0, 9) {
+            world[pos].kind = TileKind::Floor;
+        }
+        world.player_pos = Pos::new(2, 5);
+        world
+    }
+
+    pub(crate) fn kind_named(world: &World, name: &str) -> MobKind {
+        MobKind(
+            world
+                .world_info
+                .monster_kinds
+                .iter()
+                .position(|k| k.name == name)
+                .unwrap(),
+        )
+    }
+
+    pub(crate) fn spawn(world: &mut World, name: &str, pos: Pos) {
+        let kind = kind_named(world, name);
+        world.add_mob(pos, Mob::new(kind, world.get_mobkind_info(kind)));
+    }
+
+    pub(crate) fn item_named(world: &World, name: &str) -> Item {
+        let info = world
+            .world_info
+            .item_kinds
+            .iter()
+            .find(|k| k.name == name)
+            .unwrap();
+        Item::Instance(ItemInstance::new(info.clone(), STARTING_DURABILITY))
+    }
+
+    pub(crate) fn info_named(world: &World, name: &str) -> Rc<ItemInfo> {
+        match item_named(world, name) {
+            Item::Instance(instance) => instance.info,
+            Item::PendingCraft(..) => unreachable!(),
+        }
+    }
+
+    /// Adds the named item to the inventory and returns its slot.
+    pub(crate) fn give(world: &mut World, name: &str) -> usize {
+        let item = item_named(world, name);
+        world.inventory.add(item);
+        world.inventory.items.len() - 1
+    }
+
+    #[test]
+    fn headless_world_can_kill_a_monster() {
+        let mut world = test_world();
+        let sword = give(&mut world, "sword");
+        world.do_player_action(PlayerAction::Use(sword));
+        let rat_pos = world.player_pos + Offset { x: 1, y: 0 };
+        spawn(&mut world, "rat", rat_pos);
+        let rat = kind_named(&world, "rat");
+        for _ in 0..10 {
+            if world.get_mob(rat_pos).is_none() {
+                break;
+            }
+            world.do_player_action(PlayerAction::Move(Offset { x: 1, y: 0 }));
+        }
+        assert!(world.get_mob(rat_pos).is_none());
+        assert!(world.defeated.contains(&rat));
+        assert!(!world.player_is_dead());
+    }
+
+    fn armor(name: &str, slot: Option<ArmorSlot>) -> Item {
+        typed_armor(name, slot, PokemonType::Normal)
+    }
+
+    fn typed_armor(name: &str, slot: Option<ArmorSlot>, ty: PokemonType) -> Item {
+        Item::Instance(ItemInstance::new(
+            Rc::new(ItemInfo {
+                name: name.into(),
+                level: 1,
+                ty,
+                ty2: None,
+                description: String::new(),
+                kind: ItemKind::Armor,
+                unique: false,
+                slot,
+            }),
+            STARTING_DURABILITY,
+        ))
+    }
+
+    fn equipped_names(inventory: &Inventory) -> Vec<String> {
+        inventory
+            .get_equipped_armor_info()
+            .iter()
+            .map(|info| info.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn successful_craft_discovers_one_recipe() {
+        let mut world = test_world();
+        let (sword, bread, bow) = (
+            info_named(&world, "sword"),
+            info_named(&world, "bread"),
+            info_named(&world, "bow"),
+        );
+        world.world_info.recipes.insert((sword, bread), bow.clone());
+        let i = give(&mut world, "sword");
+        let j = give(&mut world, "bread");
+        assert!(world.craft_pair(i, j));
+        assert_eq!(world.discovered_recipes.len(), 1);
+        assert_eq!(world.discovered_recipes.values().next(), Some(&bow));
+    }
+
+    #[test]
+    fn pathing_is_stable_from_a_shared_borrow() {
+        let world = test_world();
+        let shared: &World = &world;
+        let (start, end) = (Pos::new(2, 5), Pos::new(6, 8));
+        let first = shared.path(start, end, 20, false, true);
+        assert!(first.is_some());
+        for _ in 0..10 {
+            assert_eq!(shared.path(start, end, 20, false, true), first);
+        }
+    }
+
+    #[test]
+    fn full_path_is_a_contiguous_cardinal_route() {
+        let mut world = test_world();
+        // A wall with a gap forces a detour.
+        for y in 0..9 {
+            world[Pos::new(8, y)].kind = TileKind::Wall;
+        }
+        let (start, end) = (Pos::new(2, 2), Pos::new(14, 2));
+        let path = world.path_full(start, end, 50, false, true).unwrap();
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&end));
+        for step in path.windows(2) {
+            assert_eq!((step[1] - step[0]).mhn_dist(), 1);
+            assert!(world[step[1]].kind.is_walkable());
+        }
+    }
+
+    #[test]
+    fn view_description_lists_the_player_and_visible_mobs() {
+        let mut world = test_world();
+        spawn(&mut world, "rat", Pos::new(5, 5));
+        let mut memory = Memory::new();
+        memory.update(&world);
+        let lines = world.describe_view(&memory);
+        assert_eq!(
+            lines[0],
+            format!("You are at 2, 5 on level 1. HP {PLAYER_MAX_HEALTH}/{PLAYER_MAX_HEALTH}.")
+        );
+        let rat_hp = world.get_mobkind_info(kind_named(&world, "rat")).max_hp();
+        assert_eq!(
+            lines[1],
+            format!("rat: 3 steps east, HP {rat_hp}/{rat_hp}.")
+        );
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn events_queue_their_sounds() {
+        let mut world = test_world();
+        let (bread, here) = (item_named(&world, "bread"), world.player_pos);
+        world[here].item = Some(bread);
+        world.do_player_action(PlayerAction::PickUp);
+        assert_eq!(world.untriggered_sounds, vec![SoundEffect::PickUp]);
+
+        world.untriggered_sounds.clear();
+        spawn(&mut world, "rat", here + Offset { x: 1, y: 0 });
+        world.do_player_action(PlayerAction::Move(Offset { x: 1, y: 0 }));
+        assert_eq!(world.untriggered_sounds.first(), Some(&SoundEffect::Attack));
+
+        world.untriggered_sounds.clear();
+        let mut hurt = false;
+        for _ in 0..10 {
+            world.do_player_action(PlayerAction::Wait);
+            world.tick();
+            hurt |= world.untriggered_sounds.contains(&SoundEffect::Hurt);
+        }
+        assert!(hurt);
+    }
+
+    #[test]
+    fn locked_stairs_wait_for_their_condition() {
+        let mut world = test_world();
+        world.world_info.areas[0].stairs_lock = Some(StairsLock::Carry("bread".into()));
+        let (stairs, dest) = (Pos::new(3, 5), Pos::new(15, 5));
+        world.add_stairs(stairs, dest);
+        let east = Offset { x: 1, y: 0 };
+        world.do_player_action(PlayerAction::Move(east));
+        assert_eq!(world.player_pos, stairs);
+        assert_eq!(world.level_id, 0);
+
+        give(&mut world, "bread");
+        world.do_player_action(PlayerAction::Move(Offset { x: -1, y: 0 }));
+        world.do_player_action(PlayerAction::Move(east));
+        assert_eq!(world.player_pos, dest);
+        assert_eq!(world.level_id, 1);
+    }
+
+    #[test]
+    fn monsters_leave_blood_only_on_plain_floor() {
+        let mut world = test_world();
+        let sword = give(&mut world, "sword");
+        world.do_player_action(PlayerAction::Use(sword));
+        let (east, west) = (Offset { x: 1, y: 0 }, Offset { x: -1, y: 0 });
+        let (floor, yellow) = (world.player_pos + east, world.player_pos + west);
+        world[yellow].kind = TileKind::YellowFloor;
+        spawn(&mut world, "rat", floor);
+        spawn(&mut world, "rat", yellow);
+        for dir in [east, west] {
+            for _ in 0..10 {
+                if world.get_mob(world.player_pos + dir).is_some() {
+                    world.do_player_action(PlayerAction::Move(dir));
+                }
+            }
+        }
+        assert!(world.mobs.is_empty());
+        assert_eq!(world[floor].kind, TileKind::BloodyFloor);
+        assert_eq!(world[yellow].kind, TileKind::YellowFloor);
+    }
+
+    #[test]
+    fn three_selected_items_craft_in_sequence() {
+        let mut world = test_world();
+        let info = |name| info_named(&world, name);
+        let recipes = [
+            ((info("sword"), info("bread")), info("bow")),
+            ((info("bow"), info("mail")), info("sword")),
+        ];
+        world.world_info.recipes.extend(recipes);
+        let selected = ["sword", "bread", "mail"].map(|name| give(&mut world, name));
+        assert!(world.do_player_action(PlayerAction::Craft(selected.to_vec())));
+        let names = world
+            .inventory
+            .items
+            .iter()
+            .filter_map(|ii| match ii.item {
+                Item::Instance(ref instance) => Some(instance.info.name.as_str()),
+                Item::PendingCraft(..) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["sword"]);
+    }
+
+    #[test]
+    fn craft_level_rules() {
+        let mut world = test_world();
+        let sword = info_named(&world, "sword");
+        let at_level = |level| {
+            let info = ItemInfo {
+                level,
+                ..(*sword).clone()
+            };
+            Item::Instance(ItemInstance::new(Rc::new(info), STARTING_DURABILITY))
+        };
+        let mut crafts = |a, b, rules: Rules| {
+            let max_diff = rules.max_craft_level_diff();
+            match world.world_info.craft(at_level(a), at_level(b), max_diff) {
+                Ok(_) => true,
+                Err(CraftError::BadLevel) => false,
+                Err(CraftError::ReagentPending) => unreachable!(),
+            }
+        };
+        let strict = Rules::default();
+        let relaxed = Rules {
+            craft_adjacent_levels: true,
+            ..strict
+        };
+        assert!(crafts(2, 2, strict));
+        assert!(!crafts(2, 3, strict));
+        assert!(crafts(2, 3, relaxed));
+        assert!(crafts(3, 2, relaxed));
+        assert!(!crafts(1, 3, relaxed));
+    }
+
+    #[test]
+    fn inspecting_with_a_monster_in_view_describes_it() {
+        let mut world = test_world();
+        spawn(&mut world, "rat", Pos::new(2, 2));
+        world.inspect_surroundings();
+        let (message, _) = world.log.back().unwrap();
+        assert_eq!(message[0].0, "rat (to the north): A rat.");
+    }
+
+    #[test]
+    fn sight_blocking_monsters_hide_what_is_behind_them() {
+        let mut world = test_world();
+        let (near, behind) = (Pos::new(4, 5), Pos::new(7, 5));
+        spawn(&mut world, "rat", near);
+        assert!(world.get_fov().contains(&behind));
+        let rat = kind_named(&world, "rat");
+        world.world_info.monster_kinds[rat.0].blocks_sight = true;
+        let fov = world.get_fov();
+        assert!(fov.contains(&near));
+        assert!(!fov.contains(&behind));
+    }
+
+    /// Ticks the world and returns the turns on which the player got hurt.
+    fn hit_turns(world: &mut World, turns: usize) -> Vec<usize> {
+        let mut hits = vec![];
+        for turn in 0..turns {
+            let before = world.player_damage;
+            world.tick();
+            if world.player_damage > before {
+                hits.push(turn);
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn only_ranged_monsters_reload() {
+        let mut world = test_world();
+        let (rat, archer) = (kind_named(&world, "rat"), kind_named(&world, "archer"));
+        assert_eq!(Mob::new(rat, world.get_mobkind_info(rat)).reload, 0);
+        assert_eq!(
+            Mob::new(archer, world.get_mobkind_info(archer)).reload,
+            RELOAD_DELAY
+        );
+
+        let next_to_player = world.player_pos + Offset { x: 1, y: 0 };
+        spawn(&mut world, "rat", next_to_player);
+        let hits = hit_turns(&mut world, 12);
+        // Melee attacks land every turn once the rat gets to act.
+        assert!(hits.windows(2).all(|w| w[1] == w[0] + 1));
+        assert!(hits.len() >= 10);
+
+        let mut world = test_world();
+        let in_range = world.player_pos + Offset { x: 4, y: 0 };
+        spawn(&mut world, "archer", in_range);
+        let hits = hit_turns(&mut world, 12);
+        assert!(hits.len() >= 2);
+        assert!(hits[0] >= RELOAD_DELAY);
+        assert!(hits.windows(2).all(|w| w[1] - w[0] > RELOAD_DELAY));
+    }
+
+    #[test]
+    fn free_roam_after_victory_is_opt_in() {
+        let east = Offset { x: 1, y: 0 };
+        for free_roam in [false, true] {
+            let mut world = test_world();
+            world.rules.free_roam_after_victory = free_roam;
+            world.victory = true;
+            let start = world.player_pos;
+            spawn(&mut world, "rat", start + Offset { x: 0, y: 1 });
+            assert_eq!(world.do_player_action(PlayerAction::Move(east)), free_roam);
+            assert_eq!(world.player_pos == start + east, free_roam);
+            // Monsters stand down either way.
+            world.do_player_action(PlayerAction::Wait);
+            assert_eq!(world.player_damage, 0);
+        }
+    }
+
+    #[test]
+    fn only_the_final_boss_wins_the_game() {
+        let mut cellar = area("cellar");
+        cellar.mini_boss = Some("rat".into());
+        let mut world = test_world_in(&[cellar]);
+        let mini_boss = world.world_info.mini_bosses[0].as_ref().unwrap().mob_kind;
+        let boss = world.world_info.boss_info.as_ref().unwrap().mob_kind;
+        for (kind, wins) in [(mini_boss, false), (boss, true)] {
+            let pos = Pos::new(10, 5);
+            world.add_mob(pos, Mob::new(kind, world.get_mobkind_info(kind)));
+            let mob = world.mobs.remove(&pos).unwrap();
+            world.damage_mob(mob, pos, usize::MAX / 2, AttackEffectiveness::One);
+            assert!(world.get_mob(pos).is_none());
+            assert_eq!(world.victory, wins);
+        }
+    }
+
+    #[test]
+    fn ranged_monsters_take_aim_before_hitting() {
+        let mut world = test_world();
+        let in_range = world.player_pos + Offset { x: 4, y: 0 };
+        spawn(&mut world, "archer", in_range);
+        let took_aim = |world: &World| {
+            world
+                .log
+                .iter()
+                .any(|(msg, _)| msg.iter().any(|(text, _)| text == " takes aim!"))
+        };
+        let mut aimed = false;
+        for _ in 0..12 {
+            world.tick();
+            if world.player_damage > 0 {
+                break;
+            }
+            aimed |= took_aim(&world);
+        }
+        assert!(aimed, "no warning before the first hit");
+        assert!(world.player_damage > 0);
+    }
+
+    #[test]
+    fn memory_keeps_tiles_out_of_view_until_seen_again() {
+        let mut world = test_world();
+        for y in 0..10 {
+            world[Pos::new(6, y)].kind = TileKind::Wall;
+        }
+        let spot = Pos::new(4, 5);
+        world[spot].item = Some(item_named(&world, "bread"));
+        let mut memory = Memory::new();
+        memory.update(&world);
+        let remembered = |memory: &Memory| memory.tile_map[spot].clone().and_then(|t| t.item);
+        assert!(remembered(&memory).is_some());
+
+        // Behind the wall, the spot is out of view but still remembered,
+        // even after its item is gone.
+        world.player_pos = Pos::new(15, 5);
+        world[spot].item = None;
+        memory.update(&world);
+        assert!(!memory.fov.contains(&spot));
+        assert!(memory.tile_map[spot].is_some());
+        assert!(remembered(&memory).is_some());
+
+        // Seeing it again shows it's empty now.
+        world.player_pos = Pos::new(2, 5);
+        memory.update(&world);
+        assert!(remembered(&memory).is_none());
+    }
+
+    #[test]
+    fn looting_monsters_take_items_they_walk_over() {
+        for rule in [false, true] {
+            let mut world = test_world();
+            world.rules.mobs_loot_items = rule;
+            let rat = kind_named(&world, "rat");
+            world.world_info.monster_kinds[rat.0].loots = true;
+            let loot = Pos::new(5, 5);
+            world[loot].item = Some(item_named(&world, "sword"));
+            spawn(&mut world, "rat", Pos::new(6, 5));
+            for _ in 0..10 {
+                if world.get_mob(loot).is_some() {
+                    break;
+                }
+                world.tick();
+            }
+            let mob = world.get_mob(loot).expect("the rat walks over the sword");
+            assert_eq!(world[loot].item.is_none(), rule);
+            assert_eq!(mob.attack_bonus, usize::from(rule));
+        }
+    }
+
+    #[test]
+    fn stab_raises_average_damage() {
+        let combat = CombatConfig::default();
+        let average = |stab| {
+            let mut total = 0;
+            for att in 1..=5 {
+                for def in 0..=3 {
+                    total +=
+                        combat.calc_damage(att, def, AttackEffectiveness::One, stab, false, false);
+                }
+            }
+            total
+        };
+        assert!(average(true) > average(false));
+    }
+
+    #[test]
+    fn stab_only_applies_when_the_rule_is_on() {
+        let mut world = test_world();
+        world.inventory.add(item_named(&world, "mail"));
+        world.inventory.toggle_equip(0);
+        let sword = world.world_info.item_kinds[0].clone();
+        assert_eq!(sword.ty, PokemonType::Normal);
+        assert!(!world.player_has_stab(&sword));
+        world.rules.stab = true;
+        assert!(world.player_has_stab(&sword));
+    }
+
+    #[test]
+    fn default_damage_formula() {
+        let combat = CombatConfig::default();
+        let hit = |att, def, eff| combat.calc_damage(att, def, eff, false, true, false);
+        assert_eq!(hit(1, 0, AttackEffectiveness::One), 8);
+        assert_eq!(hit(1, 1, AttackEffectiveness::One), 4);
+        assert_eq!(hit(1, 0, AttackEffectiveness::Two), 16);
+        assert_eq!(hit(1, 0, AttackEffectiveness::Zero), combat.min_damage);
+        assert_eq!(
+            combat.calc_damage(1, 0, AttackEffectiveness::One, false, true, true),
+            4
+        );
+    }
+
+    #[test]
+    fn manual_order_appends_pickups() {
+        let mut inventory = Inventory::new();
+        inventory.order = InventoryOrder::Manual;
+        inventory.add(armor("ring", Some(ArmorSlot::Accessory)));
+        inventory.add(armor("helm", Some(ArmorSlot::Head)));
+        inventory.toggle_equip(1);
+        inventory.arrange();
+        inventory.add(armor("mail", Some(ArmorSlot::Body)));
+        inventory.arrange();
+        let names = inventory
+            .items
+            .iter()
+            .map(|ii| match &ii.item {
+                Item::Instance(inst) => inst.info.name.as_str(),
+                Item::PendingCraft(..) => "",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["ring", "helm", "mail"]);
+    }
+
+    #[test]
+    fn armor_for_different_slots_stacks() {
+        let mut inventory = Inventory::new();
+        inventory.add(armor("helm", Some(ArmorSlot::Head)));
+        inventory.add(armor("mail", Some(ArmorSlot::Body)));
+        inventory.add(armor("ring", Some(ArmorSlot::Accessory)));
+        inventory.add(armor("cap", Some(ArmorSlot::Head)));
+        for i in 0..3 {
+            assert!(inventory.toggle_equip(i));
+        }
+        assert_eq!(equipped_names(&inventory), ["helm", "mail", "ring"]);
+
+        // A second helmet replaces the first, not some other piece.
+        assert!(inventory.toggle_equip(3));
+        assert_eq!(equipped_names(&inventory), ["mail", "ring", "cap"]);
+    }
+
+    #[test]
+    fn every_armor_piece_type_counts_against_attacks() {
+        let mut world = test_world();
+        for (name, slot, ty) in [
+            ("helm", ArmorSlot::Head, PokemonType::Normal),
+            ("mail", ArmorSlot::Body, PokemonType::Normal),
+            ("veil", ArmorSlot::Accessory, PokemonType::Ghost),
+        ] {
+            world.inventory.add(typed_armor(name, Some(slot), ty));
+            assert!(world
+                .inventory
+                .toggle_equip(world.inventory.items.len() - 1));
+        }
+        // The rat's Normal attack can't touch the Ghost-type third piece.
+        let pos = world.player_pos + Offset { x: 1, y: 0 };
+        spawn(&mut world, "rat", pos);
+        let hits = hit_turns(&mut world, 5);
+        assert!(!hits.is_empty());
+        assert_eq!(world.player_damage, hits.len() * world.combat.min_damage);
+    }
+
+    #[test]
+    fn a_light_item_grows_sight_in_unlit_areas() {
+        let mut world = test_world_in(&[Area {
+            unlit: true,
+            ..area("cave")
+        }]);
+        let (dark_fov, line_of_sight) = (world.get_fov(), world.get_line_of_sight());
+        assert!(dark_fov.len() < line_of_sight.len());
+
+        world
+            .inventory
+            .add(typed_armor("lantern", None, PokemonType::Fire));
+        assert!(world.inventory.toggle_equip(0));
+        assert!(world.get_fov().len() > dark_fov.len());
+        // Monsters see the player just as far either way.
+        assert_eq!(world.get_line_of_sight(), line_of_sight);
+    }
+
+    #[test]
+    fn taking_the_stairs_queues_one_transition() {
+        let mut world = test_world();
+        world.add_stairs(Pos::new(3, 5), Pos::new(15, 5));
+        let east = Offset { x: 1, y: 0 };
+        world.do_player_action(PlayerAction::Move(east));
+        assert_eq!(world.level_id, 1);
+        assert_eq!(world.untriggered_animations.len(), 1);
+        assert!(crate::render::plays_level_transition(
+            &world.untriggered_animations
+        ));
+
+        world.untriggered_animations.clear();
+        world.do_player_action(PlayerAction::Move(east));
+        assert!(!crate::render::plays_level_transition(
+            &world.untriggered_animations
+        ));
+    }
+
+    #[test]
+    fn firing_wakes_idle_monsters_in_earshot_out_of_sight() {
+        let mut world = test_world();
+        for y in 0..10 {
+            world[Pos::new(6, y)].kind = TileKind::Wall;
+        }
+        let bow = give(&mut world, "bow");
+        world.do_player_action(PlayerAction::Use(bow));
+        let (near, far) = (
+            world.player_pos
+                + Offset {
+                    x: FIRE_NOISE_RADIUS,
+                    y: 0,
+                },
+            Pos::new(19, 9),
+        );
+        spawn(&mut world, "rat", near);
+        spawn(&mut world, "rat", far);
+        assert!(!world.get_line_of_sight().contains(&near));
+
+        world.do_player_action(PlayerAction::Fire(Offset { x: -1, y: 0 }));
+        let player_pos = world.player_pos;
+        let woken = world
+            .mobs
+            .values()
+            .filter(|mob| matches!(mob.ai, MobAi::Move { dest } if dest == player_pos));
+        assert_eq!(woken.count(), 1);
+        assert!(matches!(world.mobs[&far].ai, MobAi::Idle));
+    }
+
+    #[test]
+    fn stale_crafts_fall_apart_after_enough_descents() {
+        let mut world = test_world();
+        let (sword, bow) = (info_named(&world, "sword"), info_named(&world, "bow"));
+        world.inventory.add(Item::PendingCraft(sword.clone(), bow));
+        world.add_stairs(Pos::new(3, 5), Pos::new(10, 5));
+        world.add_stairs(Pos::new(11, 5), Pos::new(2, 5));
+        let east = Offset { x: 1, y: 0 };
+        let is_pending =
+            |world: &World| matches!(world.inventory.items[0].item, Item::PendingCraft(..));
+        for descents in 1..=PENDING_CRAFT_MAX_LEVELS {
+            assert!(is_pending(&world));
+            world.do_player_action(PlayerAction::Move(east));
+            assert_eq!(world.level_id, descents);
+        }
+        assert!(!is_pending(&world));
+        assert!(matches!(&world.inventory.items[0].item, Item::Instance(ii) if ii.info == sword));
+    }
+
+    #[test]
+    fn memory_caches_sight_and_light_until_the_next_update() {
+        let mut world = test_world();
+        let (rat, archer) = (Pos::new(9, 5), Pos::new(4, 6));
+        spawn(&mut world, "rat", rat);
+        spawn(&mut world, "archer", archer);
+        let mut memory = Memory::new();
+        memory.update(&world);
+        assert_eq!(memory.fov, world.get_fov());
+        let view = grid::Rect::new_centered(world.player_pos, VIEW_SIZE, VIEW_SIZE);
+        assert_eq!(memory.light, world.get_light_map(view));
+        let nearest_first = memory
+            .visible_mobs(world.player_pos)
+            .iter()
+            .map(|mob| mob.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nearest_first,
+            [kind_named(&world, "archer"), kind_named(&world, "rat")]
+        );
+
+        // Changes only show up once memory is updated again.
+        let wall = Pos::new(3, 5);
+        world[wall].kind = TileKind::Wall;
+        assert_eq!(
+            memory.fov,
+            world.get_fov().union(&memory.fov).copied().collect()
+        );
+        assert_ne!(memory.fov, world.get_fov());
+        memory.update(&world);
+        assert_eq!(memory.fov, world.get_fov());
+    }
+
+    #[test]
+    fn recipes_are_found_in_either_reagent_order() {
+        let mut world = test_world();
+        let (sword, bread) = (info_named(&world, "sword"), info_named(&world, "bread"));
+        let bow = info_named(&world, "bow");
+        world
+            .world_info
+            .recipes
+            .insert((sword.clone(), bread.clone()), bow.clone());
+        assert_eq!(
+            world.world_info.get_recipe_result(&bread, &sword),
+            Some(bow.clone())
+        );
+        let i = give(&mut world, "bread");
+        let j = give(&mut world, "sword");
+        assert!(world.craft_pair(i, j));
+        assert!(matches!(&world.inventory.items[0].item, Item::Instance(ii) if ii.info == bow));
+
+        // An unknown pair is only requested once, whichever way round.
+        let mail = info_named(&world, "mail");
+        for (a, b) in [(&mail, &bread), (&bread, &mail)] {
+            let item = world.world_info.craft_inner(a.clone(), b.clone());
+            assert!(matches!(item, Item::PendingCraft(..)));
+        }
+        assert_eq!(world.world_info.pending_recipes.len(), 1);
+    }
+
+    #[test]
+    fn gear_warns_once_it_is_about_to_break() {
+        let mut world = test_world();
+        let sword = give(&mut world, "sword");
+        world.do_player_action(PlayerAction::Use(sword));
+        let Item::Instance(ii) = &mut world.inventory.items[sword].item else {
+            unreachable!()
+        };
+        ii.item_durability = 3;
+        let east = Offset { x: 1, y: 0 };
+        let warnings = |world: &World| {
+            world
+                .log
+                .iter()
+                .filter(|(line, _)| line.iter().any(|(s, _)| s.contains("about to break")))
+                .count()
+        };
+        for expected in [0, 1] {
+            let pos = world.player_pos + east;
+            spawn(&mut world, "rat", pos);
+            world.do_player_action(PlayerAction::Move(east));
+            world.mobs.remove(&pos);
+            assert_eq!(warnings(&world), expected);
+        }
+        assert_eq!(
+            world.inventory.get_about_to_break(),
+            [info_named(&world, "sword")]
+        );
+    }
+
+    #[test]
+    fn mob_ids_stay_put_as_mobs_move_and_reorder() {
+        let mut world = test_world();
+        spawn(&mut world, "rat", Pos::new(4, 5));
+        spawn(&mut world, "archer", Pos::new(8, 5));
+        let ids = |world: &World, player_pos: Pos| {
+            let mut memory = Memory::new();
+            memory.update(world);
+            memory
+                .visible_mobs(player_pos)
+                .iter()
+                .map(|mob| (mob.kind, mob.id))
+                .collect::<Vec<_>>()
+        };
+        let near_left = ids(&world, world.player_pos);
+        assert_ne!(near_left[0].1, near_left[1].1);
+
+        world.player_pos = Pos::new(10, 5);
+        let near_right = ids(&world, world.player_pos);
+        assert_eq!(near_right, [near_left[1], near_left[0]]);
+
+        let before = world.mobs.keys().copied().collect::<HashSet<_>>();
+        for _ in 0..3 {
+            world.tick();
+        }
+        assert_ne!(world.mobs.keys().copied().collect::<HashSet<_>>(), before);
+        let mut after = ids(&world, world.player_pos);
+        after.sort_by_key(|&(_, id)| id);
+        let mut expected = near_left;
+        expected.sort_by_key(|&(_, id)| id);
+        assert_eq!(after, expected);
+    }
+
+    #[test]
+    fn easy_mode_recipe_book_lists_every_recipe() {
+        let mut world = test_world();
+        let info = |name| info_named(&world, name);
+        let (sword, bread, bow, mail) = (info("sword"), info("bread"), info("bow"), info("mail"));
+        world
+            .world_info
+            .recipes
+            .insert((sword.clone(), bread.clone()), bow.clone());
+        world
+            .world_info
+            .recipes
+            .insert((bow.clone(), bread.clone()), mail.clone());
+        let i = give(&mut world, "sword");
+        let j = give(&mut world, "bread");
+        assert!(world.craft_pair(i, j));
+
+        let book = world.get_recipe_book();
+        assert_eq!(book, [(sword.clone(), bread.clone(), bow.clone())]);
+        world.rules.recipe_book_shows_all = true;
+        let book = world.get_recipe_book();
+        assert_eq!(
+            book,
+            [(bow.clone(), bread.clone(), mail), (sword, bread, bow)]
+        );
+    }
+
+    #[test]
+    fn ranged_weapons_always_have_range() {
+        // Firing has no zero-range guard; this is why it doesn't need one.
+        let info = |kind, level| ItemInfo {
+            name: "bow".into(),
+            level,
+            ty: PokemonType::Normal,
+            ty2: None,
+            description: String::new(),
+            kind,
+            unique: false,
+            slot: None,
+        };
+        for level in 0..=10 {
+            assert!(info(ItemKind::RangedWeapon, level).get_range() > 0);
+            assert_eq!(info(ItemKind::MeleeWeapon, level).get_range(), 0);
+        }
+    }
+
+    #[test]
+    fn ranged_monsters_hold_fire_with_an_ally_in_the_way() {
+        for blocked in [false, true] {
+            let mut world = test_world();
+            let (next_to_player, archer) = (
+                world.player_pos + Offset { x: 1, y: 0 },
+                world.player_pos + Offset { x: 4, y: 0 },
+            );
+            spawn(&mut world, "archer", archer);
+            world.mobs.get_mut(&archer).unwrap().reload = 0;
+            if blocked {
+                // The rat stays next to the player, right in the line of fire.
+                spawn(&mut world, "rat", next_to_player);
+            }
+            // Count aiming only from the tile the archer started on.
+            let mut aimed = false;
+            for _ in 0..12 {
+                world.tick();
+                match world.get_mob(archer) {
+                    Some(mob) => aimed |= mob.aiming_since.is_some(),
+                    None => break,
+                }
+            }
+            assert_eq!(aimed, !blocked);
+            if !blocked {
+                assert!(world.player_damage > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn turns_taken_counts_only_actions_that_take_a_turn() {
+        let mut world = test_world();
+        assert_eq!(world.turns_taken(), 0);
+        world.do_player_action(PlayerAction::Wait);
+        world.do_player_action(PlayerAction::Move(Offset { x: 1, y: 0 }));
+        assert_eq!(world.turns_taken(), 2);
+        let north = Offset { x: 0, y: -1 };
+        let wall = world.player_pos + north;
+        world[wall].kind = TileKind::Wall;
+        assert!(!world.do_player_action(PlayerAction::Move(north)));
+        assert_eq!(world.turns_taken(), 2);
+    }
+
+    #[test]
+    fn monsters_trade_places_to_pass_in_a_corridor() {
+        // Both mobs start out of the player's view, in a one-tile-wide corridor.
+        let mut world = test_world();
+        for pos in grid::Rect::new(0, 19, 0, 9) {
+            if pos.y != 5 {
+                world[pos].kind = TileKind::Wall;
+            }
+        }
+        let (west, east) = (Pos::new(12, 5), Pos::new(13, 5));
+        let find = |world: &World, id| {
+            world
+                .mobs
+                .iter()
+                .find(|(_, mob)| mob.id == id)
+                .map(|(pos, _)| *pos)
+                .unwrap()
+        };
+
+        // An idle ally makes way.
+        spawn(&mut world, "rat", west);
+        spawn(&mut world, "rat", east);
+        let (idle, mover) = (world.mobs[&west].id, world.mobs[&east].id);
+        world.mobs.get_mut(&east).unwrap().ai = MobAi::Move {
+            dest: world.player_pos,
+        };
+        for _ in 0..3 {
+            world.tick();
+        }
+        assert_eq!(find(&world, idle), east);
+        assert!(find(&world, mover).x < west.x);
+
+        // Two mobs heading opposite ways both get past each other.
+        world.mobs.clear();
+        spawn(&mut world, "rat", west);
+        spawn(&mut world, "rat", east);
+        let (eastbound, westbound) = (world.mobs[&west].id, world.mobs[&east].id);
+        world.mobs.get_mut(&west).unwrap().ai = MobAi::Move {
+            dest: Pos::new(19, 5),
+        };
+        world.mobs.get_mut(&east).unwrap().ai = MobAi::Move {
+            dest: Pos::new(10, 5),
+        };
+        for _ in 0..3 {
+            world.tick();
+        }
+        assert!(find(&world, eastbound).x > east.x);
+        assert!(find(&world, westbound).x < west.x);
+    }
+}